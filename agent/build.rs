@@ -0,0 +1,18 @@
+use std::{env, fs, path::Path};
+
+/// Embed the TOML file at `METALX_EMBEDDED_CONFIG` as the default config of the agent, used
+/// when no `--config` is given. Nothing is embedded if the variable is not set.
+fn main() {
+    println!("cargo:rerun-if-env-changed=METALX_EMBEDDED_CONFIG");
+    let config = match env::var("METALX_EMBEDDED_CONFIG") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            fs::read_to_string(&path)
+                .unwrap_or_else(|err| panic!("Failed to read embedded config {}: {}", path, err))
+        }
+        Err(_) => String::new(),
+    };
+    let out = env::var("OUT_DIR").expect("OUT_DIR is set by cargo");
+    fs::write(Path::new(&out).join("embedded_config.toml"), config)
+        .expect("Failed to write embedded config");
+}
@@ -1,35 +1,172 @@
 use anyhow::Result;
-use log::{error, trace};
+use log::{error, trace, LevelFilter};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::Read,
+    net::{IpAddr, Ipv6Addr},
+    path::Path,
+    time::Duration,
+};
+
+use crate::utils::RetryPolicy;
 
 use clap::Parser;
 
 #[derive(Parser, Debug)]
 #[command(version = "0.1.0", about = "MetalX Agent", long_about = None)]
 pub(crate) struct Args {
-    /// Configuration file
+    /// Configuration file, `-` to read TOML from stdin
     #[arg(short = 'c', long = "config")]
     pub config: Option<String>,
 
     /// Controller Address
-    #[arg(short = 'A', long = "addr")]
+    #[arg(short = 'A', long = "addr", env = "METALX_ADDR")]
     pub addr: Option<String>,
 
     /// Controller Port
-    #[arg(short = 'P', long = "port")]
+    #[arg(short = 'P', long = "port", env = "METALX_PORT")]
     pub port: Option<u16>,
 
     /// Use TLS for connection to controller
-    #[arg(short = 'S', long = "https")]
+    #[arg(short = 'S', long = "https", env = "METALX_HTTPS")]
     pub https: Option<bool>,
 
     /// API base path
-    #[arg(short = 'B', long = "api-base-path")]
+    #[arg(short = 'B', long = "api-base-path", env = "METALX_API_BASE_PATH")]
     pub api_base_path: Option<String>,
+
+    /// Token used to authenticate to controller
+    #[arg(long = "auth-token", env = "METALX_AUTH_TOKEN", hide_env_values = true)]
+    pub auth_token: Option<String>,
+
+    /// Machine UUID identifying the agent to controller, instead of the one of the DMI table
+    #[arg(long = "client-id", env = "METALX_CLIENT_ID")]
+    pub client_id: Option<String>,
+
+    /// Log and acknowledge tasks without running them
+    #[arg(long = "dry-run", env = "METALX_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Root log level (trace, debug, info, warn, error), overriding log4rs.yml
+    #[arg(long = "log-level")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Log more, -v for debug and -vv for trace
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log to this file instead of using log4rs.yml, rotated by size
+    #[arg(long = "log-file")]
+    pub log_file: Option<String>,
+
+    /// Size in bytes at which the log file is rotated
+    #[arg(long = "log-file-max-bytes", default_value_t = 10 * 1024 * 1024)]
+    pub log_file_max_bytes: u64,
+
+    /// Number of rotated log files kept
+    #[arg(long = "log-file-count", default_value_t = 5)]
+    pub log_file_count: u32,
+
+    /// Only log to the log file, not to stdout
+    #[arg(long = "log-no-console", requires = "log_file")]
+    pub log_no_console: bool,
+
+    /// Log one JSON object per line instead of using log4rs.yml, with timestamp, level,
+    /// target and message fields
+    #[arg(long = "log-json")]
+    pub log_json: bool,
+}
+
+impl Args {
+    /// Root log level requested on the command line, `--log-level` winning over `-v`.
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log_level.or(match self.verbose {
+            0 => None,
+            1 => Some(LevelFilter::Debug),
+            _ => Some(LevelFilter::Trace),
+        })
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Address of a controller the agent can register to.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub(crate) struct ControllerEndpoint {
+    pub addr: String,
+    pub port: u16,
+    #[serde(default)]
+    pub https: bool,
+}
+
+impl ControllerEndpoint {
+    /// Host part of controller URLs, IPv6 literals wrapped in brackets like `[::1]`.
+    ///
+    /// Addresses given in brackets already are used as they are.
+    fn host(&self) -> String {
+        match self.addr.parse::<Ipv6Addr>() {
+            Ok(addr) => format!("[{}]", addr),
+            Err(_) => self.addr.clone(),
+        }
+    }
+
+    /// Whether the address is a link-local IPv6 literal with a zone id like `fe80::1%eth0`.
+    fn has_zone_id(&self) -> bool {
+        let addr = self.addr.trim_start_matches('[').trim_end_matches(']');
+        addr.split_once('%')
+            .is_some_and(|(addr, _)| addr.parse::<Ipv6Addr>().is_ok())
+    }
+
+    /// Base URL of the controller API, e.g. `http://controller:1091/api/v1`.
+    pub fn api_base_url(&self, api_base_path: &str) -> String {
+        format!(
+            "{}://{}:{}/{}",
+            if self.https { "https" } else { "http" },
+            self.host(),
+            self.port,
+            api_base_path
+        )
+    }
+
+    /// Base URL of the controller websocket, e.g. `ws://controller:1091`.
+    pub fn ws_base_url(&self) -> String {
+        format!(
+            "{}://{}:{}",
+            if self.https { "wss" } else { "ws" },
+            self.host(),
+            self.port
+        )
+    }
+}
+
+/// Secret value printed as `***` by `Debug`, so it never ends up in the logs.
+///
+/// `Config` is logged with `{:?}` at startup, so every field holding a secret or pointing at
+/// one must be wrapped in this type.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub(crate) struct Redacted<T>(T);
+
+impl<T> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Redacted(value)
+    }
+
+    /// Access the secret value.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
 pub(crate) struct Config {
     /// Controller Address
     pub addr: String,
@@ -42,50 +179,816 @@ pub(crate) struct Config {
 
     /// API base path
     pub api_base_path: String,
+
+    /// Path of the register endpoint below `api_base_path`
+    pub register_path: String,
+
+    /// Path of the websocket endpoint below `api_base_path`, used unless controller names one
+    /// on registration
+    pub ws_path: String,
+
+    /// Subprotocol requested in the `Sec-WebSocket-Protocol` header of the websocket upgrade.
+    /// Connections to controllers selecting another one or none at all are given up
+    pub ws_subprotocol: Option<String>,
+
+    /// Controllers tried in order when the one at `addr` is unreachable
+    pub fallback_controllers: Vec<ControllerEndpoint>,
+
+    /// Stay connected to the controller at `addr` and every fallback controller at once,
+    /// instead of failing over between them
+    ///
+    /// A task delivered by several controllers runs once: the ids of the last
+    /// `task_dedup_window` accepted tasks are remembered and tasks reusing one of them are
+    /// ignored. Replies of a task go back over the connection that delivered it.
+    pub connect_all_controllers: bool,
+
+    /// Number of accepted task ids remembered with `connect_all_controllers`, each taking
+    /// about 50 bytes
+    pub task_dedup_window: usize,
+
+    /// Replies of tasks with an `idempotency_key` kept to answer redeliveries of a task
+    /// without running it again, least recently used first out. 0 disables the cache
+    pub idempotency_cache_size: usize,
+
+    /// Seconds the reply of a task with an `idempotency_key` is kept after it completed
+    pub idempotency_ttl_secs: u64,
+
+    /// PEM client certificate for TLS client authentication
+    pub client_cert_path: Option<String>,
+
+    /// PEM PKCS#8 private key of the client certificate, redacted in logs
+    pub client_key_path: Option<Redacted<String>>,
+
+    /// PEM CA certificate trusted for connections to controller
+    pub ca_cert_path: Option<String>,
+
+    /// Bearer token sent on registration and on the websocket upgrade, redacted in logs
+    pub auth_token: Option<Redacted<String>>,
+
+    /// Product part of the user-agent sent on every request, `MetalX-Agent/<version>` if not
+    /// set. The OS and architecture are appended, e.g. `MetalX-Agent/0.1.0 (linux; x86_64)`
+    pub user_agent: Option<String>,
+
+    /// Append the machine UUID to the user-agent, so artifact stores can tell agents apart
+    pub user_agent_machine_id: bool,
+
+    /// Addresses used for hostnames instead of asking DNS, like entries of `/etc/hosts`, e.g.
+    /// `controller = "10.0.0.5"`. They apply to registration, the websocket connection and
+    /// every download and upload, names are matched as written in URLs
+    pub hosts: HashMap<String, String>,
+
+    /// Proxy URL for plain HTTP connections, `HTTP_PROXY` if not set, redacted in logs
+    ///
+    /// Proxies apply to registration, the websocket connection and every download and
+    /// upload. The websocket is tunnelled through the proxy with CONNECT.
+    pub http_proxy: Option<Redacted<String>>,
+
+    /// Proxy URL for HTTPS connections, `HTTPS_PROXY` if not set, redacted in logs
+    pub https_proxy: Option<Redacted<String>>,
+
+    /// Time in seconds to wait for a connection to controller or a file server
+    pub connect_timeout_secs: u64,
+
+    /// Time in seconds a download may go without receiving data before it's considered
+    /// stalled, 0 to wait forever
+    pub read_timeout_secs: u64,
+
+    /// Maximum download speed of each download task in bytes per second, unlimited if unset
+    pub max_download_bytes_per_sec: Option<u64>,
+
+    /// Maximum size in bytes of a downloaded file, on top of the `max_bytes` of each task
+    pub max_download_bytes: Option<u64>,
+
+    /// Existing directory downloads are written to before they're moved to their path, next
+    /// to the path if not set
+    ///
+    /// When it's on another filesystem than the path, the finished download is copied over,
+    /// which reads and writes the whole file once more and briefly needs space for it on both.
+    pub temp_dir: Option<String>,
+
+    /// Bytes of a download collected in memory before they're written to disk, 0 to write
+    /// every received chunk on its own
+    pub download_buffer_bytes: usize,
+
+    /// Time in seconds to wait for controller to answer a `url_refresh_request`
+    pub url_refresh_timeout_secs: u64,
+
+    /// Time in seconds to wait for the whole register request to complete
+    pub register_timeout_secs: u64,
+
+    /// Consecutive failed registrations after which the agent exits, 0 to retry forever
+    ///
+    /// A registration counts as failed until its websocket is connected. With
+    /// `connect_all_controllers` each connection gives up on its own, the agent exits once all
+    /// of them did.
+    pub max_register_attempts: u32,
+
+    /// Default timeout for execute tasks in seconds, no timeout if unset
+    pub exec_timeout_secs: Option<u64>,
+
+    /// Retries of failed downloads and uploads on connection errors and 5xx responses
+    pub transfer_retries: u32,
+
+    /// Delay in seconds between transfer retries
+    pub transfer_retry_delay_secs: u64,
+
+    /// Maximum bytes of command output reported back to controller
+    pub max_output_bytes: usize,
+
+    /// Maximum bytes of a file returned by a `read_file` event
+    pub max_read_file_bytes: u64,
+
+    /// Maximum bytes of package manager output read for a `query_packages` event, packages
+    /// beyond it are left out of the reply
+    pub max_package_list_bytes: usize,
+
+    /// Maximum files a `verify_files` event may list, events with more are rejected
+    pub max_verify_files: usize,
+
+    /// Maximum bytes hashed by a `verify_files` event, files beyond it are skipped
+    pub max_verify_bytes: u64,
+
+    /// Persistent machine UUID used when the DMI table is not available
+    pub machine_id_path: String,
+
+    /// Machine UUID identifying the agent to controller. When set the DMI table and
+    /// `machine_id_path` are not read, e.g. for cloned VMs or several agents on one host
+    pub client_id: Option<String>,
+
+    /// Labels sent to controller on registration to target tasks at this agent, e.g.
+    /// `role = "gpu"`. They're read once and stay the same until the agent restarts
+    pub labels: HashMap<String, String>,
+
+    /// Name shown for the agent by controller next to its hostname. Only cosmetic, the agent is
+    /// still identified by its machine UUID
+    pub display_name: Option<String>,
+
+    /// JSON lines journal of accepted tasks, reported as interrupted to controller when the
+    /// agent starts again before they completed. Disabled if not set
+    pub journal_path: Option<String>,
+
+    /// Forward log records of the agent at this level or above to controller as `agent_log`
+    /// events, disabled if not set. Records filtered out by the log level are never forwarded
+    pub forward_log_level: Option<LevelFilter>,
+
+    /// Path whose filesystem free space is reported in status events
+    pub status_disk_path: String,
+
+    /// Port of the local HTTP health endpoint, disabled if not set
+    ///
+    /// Any `GET` request is answered with the status report as JSON, with `200` while connected
+    /// to a controller and `503` otherwise.
+    pub health_port: Option<u16>,
+
+    /// Address the health endpoint listens on
+    pub health_addr: String,
+
+    /// Port of the local HTTP metrics endpoint, disabled if not set
+    ///
+    /// Any `GET` request is answered with task, transfer and connection counters in the
+    /// Prometheus text format.
+    pub metrics_port: Option<u16>,
+
+    /// Address the metrics endpoint listens on
+    pub metrics_addr: String,
+
+    /// Initial delay in seconds before reconnecting to controller
+    pub backoff_base_secs: u64,
+
+    /// Maximum delay in seconds before reconnecting to controller
+    pub backoff_max_secs: u64,
+
+    /// Interval in seconds between Pings sent to controller, 0 to disable
+    pub ping_interval_secs: u64,
+
+    /// Time in seconds to wait for a Pong before dropping the connection
+    pub pong_timeout_secs: u64,
+
+    /// Time in seconds the websocket may go without receiving any frame before the connection
+    /// is dropped and made again, 0 to wait forever. Keep it above `ping_interval_secs`, as
+    /// controllers that send nothing else only answer Pings
+    pub idle_timeout_secs: u64,
+
+    /// Largest websocket message accepted from controller, larger ones drop the connection
+    pub ws_max_message_bytes: usize,
+
+    /// Largest websocket frame accepted from controller, larger ones drop the connection
+    pub ws_max_frame_bytes: usize,
+
+    /// Time in seconds to wait for running tasks on shutdown
+    pub shutdown_timeout_secs: u64,
+
+    /// Maximum number of tasks running at the same time, further tasks wait for a free slot
+    pub max_concurrent_tasks: usize,
+
+    /// Maximum number of tasks waiting for a free slot
+    ///
+    /// Tasks arriving while the queue is full are answered with a `task_rejected` event and
+    /// a busy code, to be sent again later by controller. The websocket is always read, so
+    /// `cancel`, `status` and Pings keep working while the agent is busy.
+    pub max_queued_tasks: usize,
+
+    /// Worker threads of the async runtime, one per CPU if not set
+    pub worker_threads: Option<usize>,
+
+    /// Acknowledge download, upload, execute and filesystem tasks without touching the
+    /// filesystem or spawning processes
+    pub dry_run: bool,
+
+    /// Base64 encoded Ed25519 public key checking the signatures of download tasks with
+    /// `verify_signature` set, such tasks fail if not set
+    pub signing_public_key: Option<String>,
+
+    /// Let controller replace the agent binary through `self_update` events
+    pub allow_self_update: bool,
+
+    /// Commands allowed in execute tasks, any command is allowed if empty
+    ///
+    /// Each entry is a regex that must match the whole first whitespace separated token of the
+    /// command, so a plain name like `systemctl` only allows that exact executable and paths
+    /// need their own entry such as `(/usr/bin/)?systemctl`. Commands with shell control
+    /// characters are rejected while the list is in use, since they could chain further
+    /// commands behind an allowed one.
+    pub command_allowlist: Vec<String>,
 }
 
 impl From<Args> for Config {
+    /// Merge command line arguments with the config file.
+    ///
+    /// Precedence from highest to lowest: CLI arguments, environment variables
+    /// (`METALX_ADDR`, `METALX_PORT`, `METALX_HTTPS`, `METALX_API_BASE_PATH`,
+    /// `METALX_AUTH_TOKEN`, `METALX_CLIENT_ID`, `METALX_DRY_RUN`), config file or stdin, the
+    /// config embedded at build time, defaults. Environment variables are resolved by clap into
+    /// `Args`, so malformed values are rejected while parsing arguments.
+    ///
+    /// The config file replaces the embedded config as a whole, they are not merged.
     fn from(args: Args) -> Self {
         let config = if let Some(path) = args.config {
             trace!("Try loading config file: {}", &path);
-            if let Ok(conf) = Config::load_toml(&path) {
-                conf
-            } else {
-                error!("Failed to load config file, fallback to default");
-                Config::default()
+            match Config::load(&path) {
+                Ok(conf) => conf,
+                Err(err) => {
+                    error!("Failed to load config file: {}, fallback to default", err);
+                    Config::embedded()
+                }
             }
         } else {
             trace!("No config file provided, use default");
-            Config::default()
+            Config::embedded()
         };
         Config {
             addr: args.addr.unwrap_or(config.addr),
             port: args.port.unwrap_or(config.port),
             https: args.https.unwrap_or(config.https),
             api_base_path: args.api_base_path.unwrap_or(config.api_base_path),
+            auth_token: args.auth_token.map(Redacted::new).or(config.auth_token),
+            client_id: args.client_id.or(config.client_id),
+            dry_run: args.dry_run || config.dry_run,
+            ..config
         }
     }
 }
 
 impl Config {
-    fn load_toml(path: &str) -> Result<Self> {
-        let mut fd = File::open(path)?;
+    /// Retry policy for file transfers.
+    pub fn transfer_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.transfer_retries,
+            delay: Duration::from_secs(self.transfer_retry_delay_secs),
+        }
+    }
+
+    /// Time a download may go without receiving data, `None` to wait forever.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        (self.read_timeout_secs > 0).then(|| Duration::from_secs(self.read_timeout_secs))
+    }
+
+    /// Time the websocket may go without receiving a frame, `None` to wait forever.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    /// Proxy for connections of the given kind, `None` to connect directly.
+    ///
+    /// Falls back to the `HTTP_PROXY` or `HTTPS_PROXY` environment variable, or its lowercase
+    /// form, when not configured.
+    pub fn proxy(&self, https: bool) -> Option<String> {
+        let (configured, var) = if https {
+            (&self.https_proxy, "HTTPS_PROXY")
+        } else {
+            (&self.http_proxy, "HTTP_PROXY")
+        };
+        if let Some(proxy) = configured {
+            return Some(proxy.expose().clone());
+        }
+        [var.to_string(), var.to_lowercase()]
+            .iter()
+            .find_map(|var| std::env::var(var).ok())
+            .filter(|proxy| !proxy.is_empty())
+    }
+
+    /// Controllers to register to in order of preference, the one at `addr` first.
+    pub fn controllers(&self) -> Vec<ControllerEndpoint> {
+        let primary = ControllerEndpoint {
+            addr: self.addr.clone(),
+            port: self.port,
+            https: self.https,
+        };
+        std::iter::once(primary)
+            .chain(self.fallback_controllers.iter().cloned())
+            .collect()
+    }
+
+    /// Address `hosts` maps `host` to, `None` if it's looked up with DNS.
+    pub fn host_addr(&self, host: &str) -> Option<IpAddr> {
+        // Addresses are checked by `validate`
+        self.hosts.get(host).and_then(|ip| ip.parse().ok())
+    }
+
+    /// Machine UUID set by `client_id`, `None` to read it from the machine.
+    pub fn client_id(&self) -> Option<uuid::Uuid> {
+        // Checked by `validate`
+        self.client_id.as_deref().and_then(|id| id.parse().ok())
+    }
+
+    /// Check the merged configuration for values that can never work.
+    pub fn validate(&self) -> Result<()> {
+        for controller in self.controllers() {
+            if controller.addr.trim().is_empty() {
+                anyhow::bail!("Controller address must not be empty");
+            }
+            if controller.port == 0 {
+                anyhow::bail!("Controller port must not be 0");
+            }
+            if controller.has_zone_id() {
+                // Not even `%25` escaped zone ids are accepted in URLs by reqwest
+                anyhow::bail!(
+                    "IPv6 zone ids are not supported in controller addresses, use a global \
+                     address or a hostname: {}",
+                    controller.addr
+                );
+            }
+        }
+        if self.api_base_path.starts_with('/') || self.api_base_path.ends_with('/') {
+            anyhow::bail!(
+                "API base path must not start or end with a slash: {}",
+                self.api_base_path
+            );
+        }
+        for path in [&self.register_path, &self.ws_path] {
+            if path.is_empty() || path.starts_with('/') || path.ends_with('/') {
+                anyhow::bail!(
+                    "Register and websocket paths must not be empty or start or end with a slash: {}",
+                    path
+                );
+            }
+        }
+        if let Some(protocol) = &self.ws_subprotocol {
+            if protocol.is_empty() || !protocol.bytes().all(|b| b.is_ascii_graphic() && b != b',') {
+                anyhow::bail!("Invalid websocket subprotocol: {:?}", protocol);
+            }
+        }
+        for (key, value) in &self.labels {
+            if key.trim().is_empty() || value.trim().is_empty() {
+                anyhow::bail!(
+                    "Label keys and values must not be empty: {:?} = {:?}",
+                    key,
+                    value
+                );
+            }
+        }
+        if self
+            .display_name
+            .as_ref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            anyhow::bail!("Display name must not be empty");
+        }
+        for (host, ip) in &self.hosts {
+            if ip.parse::<IpAddr>().is_err() {
+                anyhow::bail!("Invalid IP address of host {}: {}", host, ip);
+            }
+        }
+        if let Some(client_id) = &self.client_id {
+            uuid::Uuid::parse_str(client_id)
+                .map_err(|err| anyhow::anyhow!("Invalid client id {}: {}", client_id, err))?;
+        }
+        if let Some(key) = &self.signing_public_key {
+            crate::utils::parse_public_key(key)
+                .map_err(|err| anyhow::anyhow!("Invalid signing public key: {}", err))?;
+        }
+        if self.ws_max_message_bytes == 0 || self.ws_max_frame_bytes == 0 {
+            anyhow::bail!("Websocket message and frame size limits must not be 0");
+        }
+        if self.worker_threads == Some(0) {
+            anyhow::bail!("Worker threads must not be 0");
+        }
+        Ok(())
+    }
+
+    /// Load a config file, with the format picked by its extension.
+    ///
+    /// `.yaml`/`.yml` and `.json` files are parsed as YAML and JSON, anything else as TOML.
+    /// `-` reads TOML from stdin.
+    fn load(path: &str) -> Result<Self> {
         let buf = &mut String::new();
-        if fd.read_to_string(buf)? < 1 {
+        let read = if path == STDIN_PATH {
+            std::io::stdin().read_to_string(buf)?
+        } else {
+            File::open(path)?.read_to_string(buf)?
+        };
+        if read < 1 {
             return Err(anyhow::anyhow!(
                 "Empty config file or failed to read file content"
             ));
         }
-        let config: Config = toml::from_str(buf)?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        let config: Config = match extension.as_deref() {
+            Some("yaml") | Some("yml") => serde_yaml::from_str(buf)?,
+            Some("json") => serde_json::from_str(buf)?,
+            _ => toml::from_str(buf)?,
+        };
         Ok(config)
     }
 
+    /// The config embedded at build time from `METALX_EMBEDDED_CONFIG`, or the defaults if
+    /// none was embedded or it's invalid.
+    fn embedded() -> Self {
+        if EMBEDDED_CONFIG.is_empty() {
+            return Config::default();
+        }
+        trace!("Use embedded config");
+        toml::from_str(EMBEDDED_CONFIG).unwrap_or_else(|err| {
+            error!(
+                "Failed to parse embedded config: {}, fallback to default",
+                err
+            );
+            Config::default()
+        })
+    }
+}
+
+/// Config path reading the config from stdin instead.
+const STDIN_PATH: &str = "-";
+
+/// TOML config embedded by the build script, empty if `METALX_EMBEDDED_CONFIG` was not set.
+const EMBEDDED_CONFIG: &str = include_str!(concat!(env!("OUT_DIR"), "/embedded_config.toml"));
+
+impl Default for Config {
     fn default() -> Self {
         Config {
             addr: "controller".to_string(),
             port: 1091,
             https: false,
             api_base_path: "api/v1".to_string(),
+            register_path: "register".to_string(),
+            ws_path: "ws".to_string(),
+            ws_subprotocol: None,
+            fallback_controllers: Vec::new(),
+            connect_all_controllers: false,
+            task_dedup_window: 4096,
+            idempotency_cache_size: 1024,
+            idempotency_ttl_secs: 3600,
+            client_cert_path: None,
+            client_key_path: None,
+            ca_cert_path: None,
+            auth_token: None,
+            user_agent: None,
+            user_agent_machine_id: false,
+            hosts: HashMap::new(),
+            http_proxy: None,
+            https_proxy: None,
+            connect_timeout_secs: 30,
+            read_timeout_secs: 60,
+            max_download_bytes_per_sec: None,
+            max_download_bytes: None,
+            temp_dir: None,
+            download_buffer_bytes: 1024 * 1024,
+            url_refresh_timeout_secs: 30,
+            register_timeout_secs: 30,
+            max_register_attempts: 0,
+            exec_timeout_secs: None,
+            transfer_retries: 3,
+            transfer_retry_delay_secs: 5,
+            max_output_bytes: 64 * 1024,
+            max_read_file_bytes: 256 * 1024,
+            max_package_list_bytes: 1024 * 1024,
+            max_verify_files: 1000,
+            max_verify_bytes: 16 * 1024 * 1024 * 1024,
+            machine_id_path: "/var/lib/metalx/machine-id".to_string(),
+            client_id: None,
+            labels: HashMap::new(),
+            display_name: None,
+            journal_path: None,
+            forward_log_level: None,
+            status_disk_path: "/".to_string(),
+            health_port: None,
+            health_addr: "127.0.0.1".to_string(),
+            metrics_port: None,
+            metrics_addr: "127.0.0.1".to_string(),
+            backoff_base_secs: 15,
+            backoff_max_secs: 300,
+            ping_interval_secs: 30,
+            pong_timeout_secs: 10,
+            idle_timeout_secs: 120,
+            ws_max_message_bytes: 16 * 1024 * 1024,
+            ws_max_frame_bytes: 4 * 1024 * 1024,
+            shutdown_timeout_secs: 30,
+            max_concurrent_tasks: 4,
+            max_queued_tasks: 64,
+            worker_threads: None,
+            dry_run: false,
+            signing_public_key: None,
+            allow_self_update: false,
+            command_allowlist: Vec::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Held by tests reading `METALX_*` variables, which are shared by the whole process.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Environment variables overriding the controller settings of the config file.
+    const ENV_VARS: [&str; 4] = [
+        "METALX_ADDR",
+        "METALX_PORT",
+        "METALX_HTTPS",
+        "METALX_API_BASE_PATH",
+    ];
+
+    const FILE_CONFIG: &str = r#"
+addr = "file-host"
+port = 1111
+https = false
+api_base_path = "file/api"
+"#;
+
+    /// Write `content` to a file named `name` in the temp directory, returning its path.
+    fn write_config(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("metalx-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Merge the command line `args` with only the variables of `env` set.
+    fn config_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Config, clap::Error> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        for var in ENV_VARS {
+            std::env::remove_var(var);
+        }
+        for (var, value) in env {
+            std::env::set_var(var, value);
+        }
+        let args = Args::try_parse_from(std::iter::once("agent").chain(args.iter().copied()));
+        for (var, _) in env {
+            std::env::remove_var(var);
+        }
+        args.map(Config::from)
+    }
+
+    #[test]
+    fn defaults_apply_without_file_env_or_args() {
+        let config = config_with_env(&[], &[]).unwrap();
+        let defaults = Config::default();
+        assert_eq!(config.addr, defaults.addr);
+        assert_eq!(config.port, defaults.port);
+        assert_eq!(config.https, defaults.https);
+        assert_eq!(config.api_base_path, defaults.api_base_path);
+    }
+
+    #[test]
+    fn file_beats_defaults() {
+        let path = write_config("layer-file.toml", FILE_CONFIG);
+        let config = config_with_env(&["-c", &path], &[]).unwrap();
+        assert_eq!(config.addr, "file-host");
+        assert_eq!(config.port, 1111);
+        assert!(!config.https);
+        assert_eq!(config.api_base_path, "file/api");
+    }
+
+    #[test]
+    fn env_addr_beats_file() {
+        let path = write_config("layer-env-addr.toml", FILE_CONFIG);
+        let config = config_with_env(&["-c", &path], &[("METALX_ADDR", "env-host")]).unwrap();
+        assert_eq!(config.addr, "env-host");
+        assert_eq!(config.port, 1111);
+    }
+
+    #[test]
+    fn env_port_beats_file() {
+        let path = write_config("layer-env-port.toml", FILE_CONFIG);
+        let config = config_with_env(&["-c", &path], &[("METALX_PORT", "2222")]).unwrap();
+        assert_eq!(config.port, 2222);
+        assert_eq!(config.addr, "file-host");
+    }
+
+    #[test]
+    fn env_https_beats_file() {
+        let path = write_config("layer-env-https.toml", FILE_CONFIG);
+        let config = config_with_env(&["-c", &path], &[("METALX_HTTPS", "true")]).unwrap();
+        assert!(config.https);
+        assert_eq!(config.addr, "file-host");
+    }
+
+    #[test]
+    fn env_api_base_path_beats_file() {
+        let path = write_config("layer-env-path.toml", FILE_CONFIG);
+        let config =
+            config_with_env(&["-c", &path], &[("METALX_API_BASE_PATH", "env/api")]).unwrap();
+        assert_eq!(config.api_base_path, "env/api");
+        assert_eq!(config.addr, "file-host");
+    }
+
+    #[test]
+    fn args_beat_env() {
+        let path = write_config("layer-args.toml", FILE_CONFIG);
+        let env = [
+            ("METALX_ADDR", "env-host"),
+            ("METALX_PORT", "2222"),
+            ("METALX_HTTPS", "true"),
+            ("METALX_API_BASE_PATH", "env/api"),
+        ];
+        let args = [
+            "-c", &path, "-A", "cli-host", "-P", "3333", "-S", "false", "-B", "cli/api",
+        ];
+        let config = config_with_env(&args, &env).unwrap();
+        assert_eq!(config.addr, "cli-host");
+        assert_eq!(config.port, 3333);
+        assert!(!config.https);
+        assert_eq!(config.api_base_path, "cli/api");
+    }
+
+    #[test]
+    fn malformed_env_values_are_rejected() {
+        assert!(config_with_env(&[], &[("METALX_PORT", "not-a-port")]).is_err());
+        assert!(config_with_env(&[], &[("METALX_HTTPS", "maybe")]).is_err());
+    }
+
+    #[test]
+    fn debug_output_redacts_secrets() {
+        let config = Config {
+            auth_token: Some(Redacted::new("secret-token".to_string())),
+            client_key_path: Some(Redacted::new("/etc/metalx/secret.key".to_string())),
+            ..Default::default()
+        };
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("auth_token: Some(***)"));
+        // Other fields stay readable
+        assert!(debug.contains("addr: \"controller\""));
+    }
+
+    const TOML_CONFIG: &str = r#"
+addr = "controller.example"
+port = 8443
+https = true
+auth_token = "token"
+exec_timeout_secs = 600
+transfer_retries = 5
+
+[[fallback_controllers]]
+addr = "::1"
+port = 1091
+"#;
+
+    const YAML_CONFIG: &str = r#"
+addr: controller.example
+port: 8443
+https: true
+auth_token: token
+exec_timeout_secs: 600
+transfer_retries: 5
+fallback_controllers:
+  - addr: "::1"
+    port: 1091
+"#;
+
+    const JSON_CONFIG: &str = r#"{
+  "addr": "controller.example",
+  "port": 8443,
+  "https": true,
+  "auth_token": "token",
+  "exec_timeout_secs": 600,
+  "transfer_retries": 5,
+  "fallback_controllers": [{ "addr": "::1", "port": 1091 }]
+}"#;
+
+    #[test]
+    fn every_format_loads_the_same_config() {
+        let toml = Config::load(&write_config("format.toml", TOML_CONFIG)).unwrap();
+        assert_eq!(toml.addr, "controller.example");
+        assert_eq!(toml.exec_timeout_secs, Some(600));
+        assert_eq!(toml.fallback_controllers.len(), 1);
+        assert_ne!(toml, Config::default());
+        for (name, content) in [
+            ("format.yaml", YAML_CONFIG),
+            ("format.yml", YAML_CONFIG),
+            ("format.json", JSON_CONFIG),
+            // The extension is matched case-insensitively
+            ("format.JSON", JSON_CONFIG),
+        ] {
+            let config = Config::load(&write_config(name, content)).unwrap();
+            assert_eq!(config, toml, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unknown_extension_is_parsed_as_toml() {
+        let config = Config::load(&write_config("format.conf", TOML_CONFIG)).unwrap();
+        assert_eq!(config.addr, "controller.example");
+        // Neither sniffed as YAML or JSON, so anything but TOML is an error
+        assert!(Config::load(&write_config("yaml.conf", YAML_CONFIG)).is_err());
+        assert!(Config::load(&write_config("json.conf", JSON_CONFIG)).is_err());
+    }
+
+    #[test]
+    fn invalid_or_empty_files_are_errors() {
+        assert!(Config::load(&write_config("invalid.json", TOML_CONFIG)).is_err());
+        assert!(Config::load(&write_config("invalid.yaml", "port: [")).is_err());
+        assert!(Config::load(&write_config("empty.toml", "")).is_err());
+        assert!(Config::load("/nonexistent/metalx.toml").is_err());
+    }
+
+    fn endpoint(addr: &str) -> ControllerEndpoint {
+        ControllerEndpoint {
+            addr: addr.to_string(),
+            port: 1091,
+            https: false,
+        }
+    }
+
+    #[test]
+    fn ipv4_and_hostname_are_not_bracketed() {
+        assert_eq!(endpoint("10.0.0.5").host(), "10.0.0.5");
+        assert_eq!(endpoint("controller").host(), "controller");
+        assert_eq!(
+            endpoint("10.0.0.5").api_base_url("api/v1"),
+            "http://10.0.0.5:1091/api/v1"
+        );
+        assert_eq!(
+            endpoint("controller.example").ws_base_url(),
+            "ws://controller.example:1091"
+        );
+    }
+
+    #[test]
+    fn ipv6_is_bracketed() {
+        assert_eq!(endpoint("::1").host(), "[::1]");
+        assert_eq!(endpoint("2001:db8:0:0::1").host(), "[2001:db8::1]");
+        assert_eq!(
+            endpoint("::1").api_base_url("api/v1"),
+            "http://[::1]:1091/api/v1"
+        );
+        let tls = ControllerEndpoint {
+            https: true,
+            ..endpoint("2001:db8::1")
+        };
+        assert_eq!(tls.ws_base_url(), "wss://[2001:db8::1]:1091");
+        assert_eq!(
+            tls.api_base_url("api/v1"),
+            "https://[2001:db8::1]:1091/api/v1"
+        );
+    }
+
+    #[test]
+    fn bracketed_ipv6_is_not_wrapped_again() {
+        assert_eq!(endpoint("[::1]").host(), "[::1]");
+        assert_eq!(endpoint("[::1]").ws_base_url(), "ws://[::1]:1091");
+    }
+
+    #[test]
+    fn controller_urls_parse() {
+        for addr in ["10.0.0.5", "controller", "::1", "[::1]", "2001:db8::1"] {
+            let endpoint = endpoint(addr);
+            reqwest::Url::parse(&endpoint.api_base_url("api/v1")).unwrap();
+            reqwest::Url::parse(&endpoint.ws_base_url()).unwrap();
+        }
+    }
+
+    #[test]
+    fn zone_ids_are_rejected() {
+        for addr in ["fe80::1%eth0", "[fe80::1%eth0]"] {
+            let config = Config {
+                addr: addr.to_string(),
+                ..Default::default()
+            };
+            let err = config.validate().unwrap_err();
+            assert!(err.to_string().contains("zone ids"), "{}", err);
+        }
+        let config = Config {
+            fallback_controllers: vec![endpoint("fe80::1%2")],
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(Config::default().validate().is_ok());
+    }
+}
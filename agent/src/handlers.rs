@@ -0,0 +1,82 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use log::info;
+
+use crate::{config::Config, state::AgentState, Event, EventMessage, Outbox};
+
+/// What a handler gets to do its work with.
+pub(crate) struct TaskContext {
+    /// Outgoing websocket frames, for the replies and events sent while the task runs
+    pub tx: Outbox,
+    pub client: reqwest::Client,
+    pub config: Arc<Config>,
+    pub state: Arc<AgentState>,
+}
+
+/// Handler of the tasks of one event type.
+///
+/// It gets the event and sends `task_started`, `task_completed` and whatever else the task
+/// reports itself, an error is only logged. Events of built-in types arrive parsed and already
+/// checked against the allowlists and dry runs, events of other types arrive as
+/// `Event::Custom` with the message as received, and are handled in dry runs too:
+/// `config.dry_run` tells their handlers to leave the machine alone.
+pub(crate) type Handler =
+    Arc<dyn Fn(Event, TaskContext) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Handlers of task events by their name, so forks can add their own event types or replace
+/// the handling of built-in ones without touching the rest of the agent.
+///
+/// Every task runs through its handler the same way: it is deduplicated, queued for a free
+/// slot, journaled, can be cancelled and is rejected while shutting down. Control events like
+/// `status`, `cancel` and answers to requests of tasks have no handlers and can't be replaced.
+pub(crate) struct HandlerRegistry {
+    handlers: HashMap<&'static str, Handler>,
+    /// Task events parsed by `Event::from`
+    builtin: HashSet<&'static str>,
+}
+
+impl HandlerRegistry {
+    /// Registry of the task events parsed by `Event::from`, handled by `handlers`.
+    pub(crate) fn builtin(handlers: impl IntoIterator<Item = (&'static str, Handler)>) -> Self {
+        let mut registry = HandlerRegistry {
+            handlers: HashMap::new(),
+            builtin: HashSet::new(),
+        };
+        for (name, handler) in handlers {
+            registry.builtin.insert(name);
+            registry.register_handler(name, handler);
+        }
+        registry
+    }
+
+    /// Handle events named `name` with `handler`, replacing any earlier handler of them.
+    pub(crate) fn register_handler(&mut self, name: &'static str, handler: Handler) {
+        if self.handlers.insert(name, handler).is_some() {
+            info!("Handling of {} events replaced", name);
+        }
+    }
+
+    /// Handler of the tasks of type `kind`.
+    pub(crate) fn handler(&self, kind: &str) -> Option<Handler> {
+        self.handlers.get(kind).cloned()
+    }
+
+    /// Turn a message from controller into its event.
+    pub(crate) fn event(&self, msg: EventMessage) -> Event {
+        match Event::from(msg) {
+            // Malformed events of built-in types are left to be rejected
+            Event::Raw(msg) if !self.builtin.contains(msg.event.as_str()) => {
+                match self.handlers.get_key_value(msg.event.as_str()) {
+                    Some((kind, _)) => Event::Custom { kind, msg },
+                    None => Event::Raw(msg),
+                }
+            }
+            event => event,
+        }
+    }
+}
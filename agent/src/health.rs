@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use log::{debug, warn};
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::{timeout, Duration},
+};
+
+use crate::{config::Config, metrics, state::AgentState};
+
+/// What a local HTTP endpoint answers requests with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Endpoint {
+    /// Status report as JSON
+    Health,
+    /// Metrics in the Prometheus text format
+    Metrics,
+}
+
+/// Largest request head read from a health check client.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Time a health check client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Answer requests to `endpoint` on `listener` for as long as the agent runs.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    endpoint: Endpoint,
+    config: Config,
+    state: Arc<AgentState>,
+) {
+    let config = Arc::new(config);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(
+                    "Failed to accept {:?} endpoint connection: {}",
+                    endpoint, err
+                );
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let (config, state) = (config.clone(), state.clone());
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, endpoint, &config, &state).await {
+                debug!(
+                    "Failed to answer {:?} request from {}: {}",
+                    endpoint, peer, err
+                );
+            }
+        });
+    }
+}
+
+/// Read a request and answer it with the status report or the metrics of the agent.
+async fn respond(
+    mut stream: TcpStream,
+    endpoint: Endpoint,
+    config: &Config,
+    state: &AgentState,
+) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    // The request itself is ignored, only its end is waited for
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_BYTES {
+            return write_response(&mut stream, "431 Request Header Fields Too Large", JSON, "")
+                .await;
+        }
+        let read = timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut)??;
+        if read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..read]);
+    }
+    if !head.starts_with(b"GET ") {
+        return write_response(&mut stream, "405 Method Not Allowed", JSON, "").await;
+    }
+    if let Endpoint::Metrics = endpoint {
+        return write_response(
+            &mut stream,
+            "200 OK",
+            PROMETHEUS_TEXT,
+            &metrics::render(state),
+        )
+        .await;
+    }
+    let connected = state.connection.lock().unwrap().open > 0;
+    let mut status = state.status(config);
+    status.insert("connected".to_string(), json!(connected));
+    status.insert(
+        "running_tasks".to_string(),
+        json!(state.tasks.lock().unwrap().len()),
+    );
+    let code = if connected {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    write_response(&mut stream, code, JSON, &json!(status).to_string()).await
+}
+
+/// Content type of the status report.
+const JSON: &str = "application/json";
+
+/// Content type of the Prometheus text exposition format.
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Point in the life of a task recorded in the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TaskStatus {
+    Started,
+    Completed,
+    /// Started by an earlier run of the agent and reported to controller as interrupted
+    Interrupted,
+}
+
+/// One line of the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    pub id: u64,
+    /// Event type of the task, e.g. `download`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub status: TaskStatus,
+    /// Unix time in seconds the entry was written
+    pub time: u64,
+}
+
+/// Append-only record of accepted tasks, kept as JSON lines so tasks cut short by a crash or
+/// restart can be told to controller on the next start.
+pub(crate) struct Journal {
+    path: String,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Open the journal at `path`, returning the tasks an earlier run left unfinished.
+    ///
+    /// The file is rewritten to hold only those tasks so it doesn't grow across runs.
+    pub(crate) fn open(path: &str) -> Result<(Self, Vec<JournalEntry>)> {
+        let unfinished = match File::open(path) {
+            Ok(file) => read_unfinished(path, file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read task journal {}", path))
+            }
+        };
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut compacted = String::new();
+        for entry in &unfinished {
+            compacted += &serde_json::to_string(entry)?;
+            compacted.push('\n');
+        }
+        std::fs::write(path, compacted)
+            .with_context(|| format!("Failed to write task journal {}", path))?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        for entry in &unfinished {
+            warn!(
+                "Task was interrupted by the previous run: id = {}, type = {}",
+                entry.id, entry.kind
+            );
+        }
+        let journal = Journal {
+            path: path.to_string(),
+            file: Mutex::new(file),
+        };
+        Ok((journal, unfinished))
+    }
+
+    /// Append an entry for the task `id`. Failures are logged, tasks go on without a record.
+    pub(crate) fn record(&self, id: u64, kind: &str, status: TaskStatus) {
+        let entry = JournalEntry {
+            id,
+            kind: kind.to_string(),
+            status,
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default(),
+        };
+        // Serializing plain fields can't fail
+        let mut line = serde_json::to_string(&entry).unwrap_or_default();
+        line.push('\n');
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write task journal {}: {}", self.path, err);
+        }
+    }
+}
+
+/// Collect the tasks whose last entry says they were started, in the order they were started.
+fn read_unfinished(path: &str, file: File) -> Vec<JournalEntry> {
+    let mut last: HashMap<u64, JournalEntry> = HashMap::new();
+    let mut order = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("Failed to read task journal {}: {}", path, err);
+                break;
+            }
+        };
+        // A crash may leave the last line half-written
+        let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) else {
+            warn!("Skipping invalid line of task journal {}", path);
+            continue;
+        };
+        if entry.status == TaskStatus::Started {
+            order.push(entry.id);
+        }
+        last.insert(entry.id, entry);
+    }
+    order
+        .into_iter()
+        .filter_map(|id| last.remove(&id))
+        .filter(|entry| entry.status == TaskStatus::Started)
+        .collect()
+}
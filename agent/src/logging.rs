@@ -0,0 +1,274 @@
+use std::{
+    future::Future,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use log::{Level, LevelFilter, Record};
+use log4rs::{
+    append::Append,
+    append::{
+        console::ConsoleAppender,
+        rolling_file::{
+            policy::compound::{
+                roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger, CompoundPolicy,
+            },
+            RollingFileAppender,
+        },
+    },
+    config::{Appender, Config, Deserialize, Deserializers, Root},
+    encode::{self, json::JsonEncoder, pattern::PatternEncoder, Encode},
+};
+use tokio::sync::mpsc;
+
+use crate::config::Args;
+
+/// log4rs configuration file, looked up in the working directory.
+const CONFIG_FILE: &str = "log4rs.yml";
+
+/// Name and `log4rs.yml` kind of the appender forwarding records to controller.
+const CONTROLLER_APPENDER: &str = "controller";
+
+/// MDC key holding the id of the task a record was logged by, `{X(task_id)}` in patterns.
+const TASK_ID_KEY: &str = "task_id";
+
+/// Records waiting to be forwarded, dropped once this many are queued.
+const FORWARD_QUEUE_SIZE: usize = 1024;
+
+/// Queue of records for controller, set once by `forward_to_controller`.
+static FORWARD: OnceLock<Forward> = OnceLock::new();
+
+struct Forward {
+    level: LevelFilter,
+    tx: mpsc::Sender<ForwardedRecord>,
+}
+
+/// A log record of the agent queued to be sent to controller.
+#[derive(Debug)]
+pub(crate) struct ForwardedRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    /// Unix time in seconds the record was logged
+    pub time: u64,
+    /// Task the record was logged by, if any
+    pub task_id: Option<u64>,
+}
+
+/// Run `future` with records it logs tagged with the task `id`.
+///
+/// The id is kept in the MDC of the thread polling `future` only while it's polled, so tasks
+/// moving between worker threads or sharing one never see each other's id. Tasks `future`
+/// spawns itself are not tagged.
+pub(crate) async fn with_task_id<F: Future>(id: u64, future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    let id = id.to_string();
+    std::future::poll_fn(|cx| {
+        let _guard = log_mdc::insert_scoped(TASK_ID_KEY, id.as_str());
+        future.as_mut().poll(cx)
+    })
+    .await
+}
+
+/// Id of the task logging on this thread, set by `with_task_id`.
+fn current_task_id() -> Option<u64> {
+    log_mdc::get(TASK_ID_KEY, |id| id.and_then(|id| id.parse().ok()))
+}
+
+/// Start queueing records at `level` or above for controller and return the queue.
+///
+/// Only records of the agent itself are forwarded. Records of the libraries sending the
+/// forwarded ones would otherwise come back with every record sent. Records are dropped while
+/// the queue is full, so logging never waits on controller.
+///
+/// The appender is part of the configurations the agent builds. A `log4rs.yml` needs an
+/// appender of kind `controller` on its root for records to be forwarded.
+pub(crate) fn forward_to_controller(level: LevelFilter) -> mpsc::Receiver<ForwardedRecord> {
+    let (tx, rx) = mpsc::channel(FORWARD_QUEUE_SIZE);
+    if FORWARD.set(Forward { level, tx }).is_err() {
+        log::warn!("Log records are already forwarded to controller");
+    }
+    rx
+}
+
+/// Appender pushing records into the queue of `forward_to_controller`, if started.
+#[derive(Debug)]
+struct ControllerAppender;
+
+impl Append for ControllerAppender {
+    fn append(&self, record: &Record) -> Result<()> {
+        let Some(forward) = FORWARD.get() else {
+            return Ok(());
+        };
+        let target = record.target();
+        let own = target == env!("CARGO_CRATE_NAME")
+            || target
+                .strip_prefix(env!("CARGO_CRATE_NAME"))
+                .is_some_and(|rest| rest.starts_with("::"));
+        if !own || record.level() > forward.level {
+            return Ok(());
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        // Dropped when full or when nothing drains the queue anymore
+        _ = forward.tx.try_send(ForwardedRecord {
+            level: record.level(),
+            target: target.to_string(),
+            message: record.args().to_string(),
+            time,
+            task_id: current_task_id(),
+        });
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Pattern encoder putting the task id in front of the messages records logged by tasks.
+#[derive(Debug, Default)]
+struct TaskEncoder(PatternEncoder);
+
+impl Encode for TaskEncoder {
+    fn encode(&self, w: &mut dyn encode::Write, record: &Record) -> Result<()> {
+        match current_task_id() {
+            Some(id) => self.0.encode(
+                w,
+                &record
+                    .to_builder()
+                    .args(format_args!("[task {}] {}", id, record.args()))
+                    .build(),
+            ),
+            None => self.0.encode(w, record),
+        }
+    }
+}
+
+/// Config of a `controller` appender in `log4rs.yml`, which takes no options.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControllerAppenderConfig {}
+
+struct ControllerAppenderDeserializer;
+
+impl Deserialize for ControllerAppenderDeserializer {
+    type Trait = dyn Append;
+    type Config = ControllerAppenderConfig;
+
+    fn deserialize(
+        &self,
+        _config: ControllerAppenderConfig,
+        _deserializers: &Deserializers,
+    ) -> Result<Box<dyn Append>> {
+        Ok(Box::new(ControllerAppender))
+    }
+}
+
+/// Deserializers for `log4rs.yml`, the log4rs ones plus the `controller` appender.
+fn deserializers() -> Deserializers {
+    let mut deserializers = Deserializers::default();
+    deserializers.insert(CONTROLLER_APPENDER, ControllerAppenderDeserializer);
+    deserializers
+}
+
+/// The appender forwarding records to controller, added to every configuration built here.
+fn controller_appender() -> Appender {
+    Appender::builder().build(CONTROLLER_APPENDER, Box::new(ControllerAppender))
+}
+
+/// Initialize logging as requested on the command line.
+///
+/// With `--log-file` or `--log-json` the configuration is built programmatically and
+/// `log4rs.yml` is not read. Otherwise `log4rs.yml` is used, falling back to Debug-level
+/// stdout if it's missing. `--log-level` and `-v` override the root level of any of them.
+/// Returns `false` if no logger could be installed at all.
+pub(crate) fn init(args: &Args) -> bool {
+    let level = args.log_level();
+    let result = if args.log_file.is_some() || args.log_json {
+        built_config(args, level.unwrap_or(LevelFilter::Info)).and_then(|config| {
+            log4rs::init_config(config)?;
+            Ok(())
+        })
+    } else {
+        match level {
+            // Keep the file watched for changes unless something has to be overridden
+            None => log4rs::init_file(CONFIG_FILE, deserializers()),
+            Some(level) => log4rs::config::load_config_file(CONFIG_FILE, deserializers()).and_then(
+                |mut config| {
+                    config.root_mut().set_level(level);
+                    log4rs::init_config(config)?;
+                    Ok(())
+                },
+            ),
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("Failed to initialize log4rs: {}", err);
+        if let Ok(config) = Config::builder()
+            .appender(
+                Appender::builder().build(
+                    "stdout",
+                    Box::new(
+                        ConsoleAppender::builder()
+                            .encoder(Box::<TaskEncoder>::default())
+                            .build(),
+                    ),
+                ),
+            )
+            .appender(controller_appender())
+            .build(
+                Root::builder()
+                    .appender("stdout")
+                    .appender(CONTROLLER_APPENDER)
+                    .build(level.unwrap_or(LevelFilter::Debug)),
+            )
+        {
+            if log4rs::init_config(config).is_err() {
+                eprintln!("Failed to initialize log4rs with default config");
+                return false;
+            }
+        } else {
+            eprintln!("Failed to construct default log4rs config");
+            return false;
+        }
+    }
+    true
+}
+
+/// Log to the `--log-file`, rotated by size into `path.1` .. `path.N`, and to stdout unless
+/// disabled, as JSON with `--log-json`.
+fn built_config(args: &Args, level: LevelFilter) -> Result<Config> {
+    let mut builder = Config::builder().appender(controller_appender());
+    let mut root = Root::builder().appender(CONTROLLER_APPENDER);
+    if let Some(path) = &args.log_file {
+        let roller = FixedWindowRoller::builder()
+            .base(1)
+            .build(&format!("{}.{{}}", path), args.log_file_count)?;
+        let policy = CompoundPolicy::new(
+            Box::new(SizeTrigger::new(args.log_file_max_bytes)),
+            Box::new(roller),
+        );
+        let file = RollingFileAppender::builder()
+            .encoder(encoder(args))
+            .build(path, Box::new(policy))?;
+        builder = builder.appender(Appender::builder().build("file", Box::new(file)));
+        root = root.appender("file");
+    }
+    if !args.log_no_console {
+        let console = ConsoleAppender::builder().encoder(encoder(args)).build();
+        builder = builder.appender(Appender::builder().build("stdout", Box::new(console)));
+        root = root.appender("stdout");
+    }
+    Ok(builder.build(root.build(level))?)
+}
+
+/// Format of log lines requested on the command line. JSON lines carry the task id in `mdc`.
+fn encoder(args: &Args) -> Box<dyn Encode> {
+    if args.log_json {
+        Box::new(JsonEncoder::new())
+    } else {
+        Box::<TaskEncoder>::default()
+    }
+}
@@ -1,47 +1,453 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use clap::Parser;
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use log::{debug, trace, warn, LevelFilter};
+use config::ControllerEndpoint;
+use futures_util::{SinkExt, StreamExt};
+use handlers::{Handler, HandlerRegistry, TaskContext};
+use health::Endpoint;
+use journal::TaskStatus;
+use log::{debug, trace, warn};
 use log::{error, info};
-use log4rs::append::console::ConsoleAppender;
-use log4rs::config::{Appender, Config, Root};
 use maplit::hashmap;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use state::{AgentState, RunningTask};
 use std::collections::HashMap;
-use utils::{download_file, execute_shell, upload_file};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, Duration, Instant, MissedTickBehavior};
+use utils::integrity::{self, FileCheck, FileState};
+use utils::mirror;
+use utils::packages;
+use utils::system_info::SystemInfo;
+use utils::{
+    download_file, execute_command, execute_command_with_callback, execute_command_with_output,
+    execute_command_with_split_output, upload_command_output, upload_file, Backoff,
+    ChecksumMismatch, CommandExit, CommandOptions, CommandTimeout, Compression, DownloadOptions,
+    DownloadOutcome, HttpStatusError, InsufficientDiskSpace, OutputStream, ProgressCallback, Shell,
+    UnauthorizedArtifact, UploadOptions,
+};
+use uuid::Uuid;
 mod config;
+mod handlers;
+mod health;
+mod journal;
+mod logging;
+mod metrics;
+mod net;
+mod state;
 mod utils;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use tokio_tungstenite::tungstenite::{
+    error::CapacityError,
+    protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    Error as WsError,
+};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct EventMessage {
     id: u64,
     code: i32,
     event: String,
+    /// Key of a task controller may deliver more than once, a task whose key completed
+    /// recently gets its earlier reply again instead of running twice
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
     data: Option<HashMap<String, serde_json::Value>>,
 }
 
-struct FileDownloadUploadTask {
+struct FileDownloadTask {
     id: u64,
     url: String,
     path: String,
+    sha256: Option<String>,
+    resume: bool,
+    decompress: Option<Compression>,
+    max_bytes: Option<u64>,
+    headers: Option<HashMap<String, String>>,
+    mode: Option<String>,
+    /// Further destinations the file is copied to once it's downloaded to `path`
+    copies: Vec<String>,
+    /// Servers of the same file fetched from in parallel, `url` is only used if they fail
+    mirrors: Vec<String>,
+    /// Shell command run once the file was downloaded and verified
+    post_hook: Option<String>,
+    /// Check the file against `signature` or the one at `signature_url`
+    verify_signature: bool,
+    /// Detached signature as base64
+    signature: Option<String>,
+    signature_url: Option<String>,
+}
+
+struct FileUploadTask {
+    id: u64,
+    url: String,
+    path: String,
+    multipart: bool,
+    field_name: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    /// Send the file as it grows, see [`UploadOptions::tail`]
+    tail: bool,
+    follow_secs: Option<u64>,
 }
 
 struct ExecuteTask {
     id: u64,
     cmd: String,
+    timeout_secs: Option<u64>,
+    capture_output: bool,
+    /// Report captured stdout and stderr apart instead of merged
+    split_output: bool,
+    cwd: Option<String>,
+    env: Option<HashMap<String, String>>,
+    env_clear: bool,
+    stdin: Option<String>,
+    /// Name of the shell running `cmd`, the platform default if unset
+    shell: Option<String>,
+    /// Arguments `cmd` is run with directly instead of through a shell, for `execute_argv`
+    args: Option<Vec<String>>,
+    /// Condition the command is only run under, for `execute` and `execute_argv`
+    condition: Option<Precondition>,
+}
+
+/// State of the machine an execute task requires, the task is skipped otherwise.
+enum Precondition {
+    FileExists(String),
+    FileMissing(String),
+}
+
+impl Precondition {
+    /// Parse `only_if_file_exists` or `only_if_file_missing`, failing if both are set.
+    fn from_data(data: &HashMap<String, Value>) -> Result<Option<Self>, String> {
+        match (
+            json_str(data, "only_if_file_exists"),
+            json_str(data, "only_if_file_missing"),
+        ) {
+            (Some(_), Some(_)) => {
+                Err("only_if_file_exists and only_if_file_missing can't both be set".to_string())
+            }
+            (Some(path), None) => Ok(Some(Precondition::FileExists(path))),
+            (None, Some(path)) => Ok(Some(Precondition::FileMissing(path))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Why the task is skipped, `None` if the condition is met.
+    async fn unmet(&self) -> Option<String> {
+        match self {
+            Precondition::FileExists(path) => {
+                (!file_exists(path).await).then(|| format!("{} does not exist", path))
+            }
+            Precondition::FileMissing(path) => {
+                file_exists(path).await.then(|| format!("{} exists", path))
+            }
+        }
+    }
+}
+
+/// Whether something exists at `path`, following symlinks.
+async fn file_exists(path: &str) -> bool {
+    tokio::fs::try_exists(path).await.unwrap_or(false)
+}
+
+impl ExecuteTask {
+    /// Parse the command of an `execute`-like event, `None` if it has no `cmd` and an error if
+    /// its `env` is malformed.
+    fn from_data(id: u64, data: &HashMap<String, Value>) -> Result<Option<Self>, String> {
+        let Some(cmd) = json_str(data, "cmd") else {
+            return Ok(None);
+        };
+        Ok(Some(ExecuteTask {
+            id,
+            cmd,
+            timeout_secs: json_int(data, "timeout_secs").and_then(|v| u64::try_from(v).ok()),
+            capture_output: json_bool(data, "capture_output").unwrap_or(false),
+            split_output: json_bool(data, "split_output").unwrap_or(false),
+            cwd: json_str(data, "cwd"),
+            env: json_env(data)?,
+            env_clear: json_bool(data, "env_clear").unwrap_or(false),
+            stdin: json_str(data, "stdin"),
+            shell: json_str(data, "shell"),
+            args: None,
+            condition: None,
+        }))
+    }
+
+    /// The shell asked for, failing if it's not supported.
+    fn shell(&self) -> Result<Shell> {
+        match &self.shell {
+            Some(name) => Shell::parse(name).ok_or_else(|| anyhow!("Unsupported shell: {}", name)),
+            None => Ok(Shell::default()),
+        }
+    }
+
+    /// Program and arguments to run, `cmd` itself with `args` or the shell running `cmd`.
+    fn command(&self) -> Result<(String, Vec<String>)> {
+        match &self.args {
+            Some(args) => Ok((self.cmd.clone(), args.clone())),
+            None => Ok(self.shell()?.command(&self.cmd)),
+        }
+    }
+
+    fn command_options<'a>(&'a self, config: &config::Config) -> CommandOptions<'a> {
+        CommandOptions {
+            timeout: self
+                .timeout_secs
+                .or(config.exec_timeout_secs)
+                .map(Duration::from_secs),
+            cwd: self.cwd.as_deref(),
+            env: self.env.as_ref(),
+            env_clear: self.env_clear,
+            stdin: self.stdin.as_deref(),
+        }
+    }
+}
+
+/// Upload of a command's output, which is streamed without touching the disk.
+struct UploadStreamTask {
+    url: String,
+    exec: ExecuteTask,
+}
+
+struct SelfUpdateTask {
+    id: u64,
+    url: String,
+    sha256: String,
 }
 
 enum Event {
-    Download(FileDownloadUploadTask),
-    Upload(FileDownloadUploadTask),
+    Download(FileDownloadTask),
+    Upload(FileUploadTask),
     Execute(ExecuteTask),
+    /// Like `Execute`, running `cmd` with `args` without a shell
+    ExecuteArgv(ExecuteTask),
+    ExecuteStream(ExecuteTask),
+    UploadStream(UploadStreamTask),
+    SelfUpdate(SelfUpdateTask),
+    FsMove {
+        id: u64,
+        src: String,
+        dst: String,
+    },
+    FsCopy {
+        id: u64,
+        src: String,
+        dst: String,
+    },
+    FsDelete {
+        id: u64,
+        path: String,
+        recursive: bool,
+    },
+    ReadFile {
+        id: u64,
+        path: String,
+        max_bytes: Option<u64>,
+    },
+    /// List of installed packages for compliance checks
+    QueryPackages(u64),
+    /// Files compared against their expected digests
+    VerifyFiles {
+        id: u64,
+        files: Vec<FileCheck>,
+    },
+    Status(u64),
+    Echo {
+        id: u64,
+        data: Option<HashMap<String, Value>>,
+    },
+    Cancel {
+        id: u64,
+        target: u64,
+    },
+    /// Answer of controller to a request made by a running task
+    Reply(EventMessage),
+    /// Event of a type added with `HandlerRegistry::register_handler`
+    Custom {
+        kind: &'static str,
+        msg: EventMessage,
+    },
+    /// Event of a known type with malformed data, rejected with `error`
+    Invalid {
+        id: u64,
+        error: String,
+    },
     Raw(EventMessage),
 }
 
+impl Event {
+    /// Id of a task that runs in the background, `None` for events handled right away.
+    fn task_id(&self) -> Option<u64> {
+        match self {
+            Event::Download(task) => Some(task.id),
+            Event::Upload(task) => Some(task.id),
+            Event::Execute(task) | Event::ExecuteArgv(task) | Event::ExecuteStream(task) => {
+                Some(task.id)
+            }
+            Event::UploadStream(task) => Some(task.exec.id),
+            Event::SelfUpdate(task) => Some(task.id),
+            Event::FsMove { id, .. }
+            | Event::FsCopy { id, .. }
+            | Event::FsDelete { id, .. }
+            | Event::ReadFile { id, .. } => Some(*id),
+            Event::QueryPackages(id) | Event::VerifyFiles { id, .. } => Some(*id),
+            Event::Custom { msg, .. } => Some(msg.id),
+            Event::Status(_)
+            | Event::Echo { .. }
+            | Event::Cancel { .. }
+            | Event::Reply(_)
+            | Event::Invalid { .. }
+            | Event::Raw(_) => None,
+        }
+    }
+
+    /// Event type, as sent in `task_started` and recorded in the journal.
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::Download(_) => "download",
+            Event::Upload(_) => "upload",
+            Event::Execute(_) => "execute",
+            Event::ExecuteArgv(_) => "execute_argv",
+            Event::ExecuteStream(_) => "execute_stream",
+            Event::UploadStream(_) => "upload_stream",
+            Event::SelfUpdate(_) => "self_update",
+            Event::FsMove { .. } => "fs_move",
+            Event::FsCopy { .. } => "fs_copy",
+            Event::FsDelete { .. } => "fs_delete",
+            Event::ReadFile { .. } => "read_file",
+            Event::QueryPackages(_) => "query_packages",
+            Event::VerifyFiles { .. } => "verify_files",
+            Event::Custom { kind, .. } => kind,
+            Event::Status(_) => "status",
+            Event::Echo { .. } => "echo",
+            Event::Cancel { .. } => "cancel",
+            Event::Reply(_) => "reply",
+            Event::Invalid { .. } => "invalid",
+            Event::Raw(_) => "raw",
+        }
+    }
+
+    /// File the task may leave half-written when cancelled.
+    fn partial_file(&self, config: &config::Config) -> Option<String> {
+        match self {
+            Event::Download(task) => {
+                Some(utils::partial_path(&task.path, config.temp_dir.as_deref()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Version of the event protocol spoken by this agent, sent on registration.
+///
+/// Controllers answer the version they speak in the `protocol_version` of the registration
+/// data. The agent refuses to connect to a controller speaking another version.
+const PROTOCOL_VERSION: i64 = 1;
+
+/// Error returned by `agent_main` once `max_register_attempts` registrations failed in a row.
+#[derive(Debug)]
+struct RegisterAttemptsExhausted {
+    attempts: u32,
+}
+
+impl std::fmt::Display for RegisterAttemptsExhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Registration failed {} times in a row", self.attempts)
+    }
+}
+
+impl std::error::Error for RegisterAttemptsExhausted {}
+
+/// Describe why a registration request failed, telling a controller that never answers from
+/// one that can't be reached.
+fn register_error(err: &reqwest::Error, config: &config::Config) -> String {
+    match (err.is_connect(), err.is_timeout()) {
+        (true, true) => format!(
+            "Timed out connecting to controller after {} seconds",
+            config.connect_timeout_secs
+        ),
+        (false, true) => format!(
+            "Controller accepted the connection but didn't answer registration in {} seconds",
+            config.register_timeout_secs
+        ),
+        (true, false) => {
+            // The message of reqwest only names the URL, its cause says refused or unreachable
+            let mut cause: &dyn std::error::Error = err;
+            while let Some(source) = cause.source() {
+                cause = source;
+            }
+            cause.to_string()
+        }
+        (false, false) => err.to_string(),
+    }
+}
+
+/// Count a failed registration, giving up once `max_register_attempts` is reached.
+fn register_failed(failures: &mut u32, config: &config::Config) -> Result<()> {
+    *failures += 1;
+    if config.max_register_attempts > 0 && *failures >= config.max_register_attempts {
+        return Err(RegisterAttemptsExhausted {
+            attempts: *failures,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Result code of a reply to controller, sent as the `code` of the event message.
+///
+/// Execute tasks that ran their command report its exit code instead, so codes of agent
+/// failures are kept out of the range of exit codes. Wire values must not change, controllers
+/// match on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+enum TaskResultCode {
+    Success = 0,
+    /// The task failed, e.g. a transfer or file operation, with the reason in `error`
+    Failed = 1,
+    /// The command of an execute task ran out of time
+    Timeout = -2,
+    /// The event type is not known to the agent
+    UnknownEvent = 0x80000000u32 as i32,
+    /// The task was aborted by a `cancel` event
+    Cancelled = 0x80000001u32 as i32,
+    /// The task was rejected by `command_allowlist` or `allow_self_update`
+    PermissionDenied = 0x80000002u32 as i32,
+    /// The command of an execute task could not be run at all
+    ExecFailed = 0x80000003u32 as i32,
+    /// The frame from controller is not a valid event message
+    InvalidMessage = 0x80000004u32 as i32,
+    /// The task was rejected because `max_queued_tasks` tasks are already waiting
+    Busy = 0x80000005u32 as i32,
+    /// The task was rejected because the agent is shutting down
+    ShuttingDown = 0x80000006u32 as i32,
+    /// The execute task was not run because its `only_if_file_*` condition isn't met
+    Skipped = 0x80000007u32 as i32,
+}
+
+impl From<TaskResultCode> for i32 {
+    fn from(code: TaskResultCode) -> Self {
+        code as i32
+    }
+}
+
+impl TaskResultCode {
+    /// `Success` or `Failed` depending on whether the task went through.
+    fn of<T, E>(result: &std::result::Result<T, E>) -> Self {
+        if result.is_ok() {
+            TaskResultCode::Success
+        } else {
+            TaskResultCode::Failed
+        }
+    }
+}
+
 fn json_str(map: &HashMap<String, Value>, key: &str) -> Option<String> {
-    map.get(key).map_or(None, |v| {
+    map.get(key).and_then(|v| {
         if let Value::String(v2) = v {
             Some(v2.clone())
         } else {
@@ -50,18 +456,58 @@ fn json_str(map: &HashMap<String, Value>, key: &str) -> Option<String> {
     })
 }
 
-// fn json_int(map: &HashMap<String, Value>, key: &str) -> Option<i64> {
-//     map.get(key).map_or(None, |v| {
-//         if let Value::Number(v2) = v {
-//             v2.as_i64()
-//         } else {
-//             None
-//         }
-//     })
-// }
+fn json_int(map: &HashMap<String, Value>, key: &str) -> Option<i64> {
+    map.get(key).and_then(|v| {
+        if let Value::Number(v2) = v {
+            v2.as_i64()
+        } else {
+            None
+        }
+    })
+}
+
+/// Environment variables of an execute task, numbers and booleans taken as their JSON text.
+fn json_env(map: &HashMap<String, Value>) -> Result<Option<HashMap<String, String>>, String> {
+    let vars = match map.get("env") {
+        None => return Ok(None),
+        Some(Value::Object(vars)) => vars,
+        Some(_) => return Err("env must be an object of variables".to_string()),
+    };
+    vars.iter()
+        .map(|(name, value)| match value {
+            Value::String(value) => Ok((name.clone(), value.clone())),
+            Value::Number(_) | Value::Bool(_) => Ok((name.clone(), value.to_string())),
+            _ => Err(format!(
+                "env variable {} must be a string, number or boolean",
+                name
+            )),
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Object of strings under `key`, an error if any of its values isn't a string.
+fn json_str_map(
+    map: &HashMap<String, Value>,
+    key: &str,
+) -> Result<Option<HashMap<String, String>>, String> {
+    let Some(value) = map.get(key) else {
+        return Ok(None);
+    };
+    let invalid = || format!("{} must be an object of strings", key);
+    let Value::Object(entries) = value else {
+        return Err(invalid());
+    };
+    entries
+        .iter()
+        .map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+        .collect::<Option<_>>()
+        .map(Some)
+        .ok_or_else(invalid)
+}
 
 fn json_bool(map: &HashMap<String, Value>, key: &str) -> Option<bool> {
-    map.get(key).map_or(None, |v| {
+    map.get(key).and_then(|v| {
         if let Value::Bool(v2) = v {
             Some(*v2)
         } else {
@@ -75,12 +521,92 @@ impl From<EventMessage> for Event {
         match msg.event.as_str() {
             "download" => {
                 if let Some(data) = msg.data.as_ref() {
+                    // `paths` lists several destinations, downloaded once to the first one
+                    let mut paths: Vec<String> = json_str(data, "path").into_iter().collect();
+                    match data.get("paths") {
+                        None => {}
+                        Some(Value::Array(list)) if !list.is_empty() => {
+                            let Some(list) =
+                                list.iter().map(Value::as_str).collect::<Option<Vec<_>>>()
+                            else {
+                                return Event::Invalid {
+                                    id: msg.id,
+                                    error: "paths must only contain strings".to_string(),
+                                };
+                            };
+                            for path in list {
+                                if !paths.iter().any(|known| known == path) {
+                                    paths.push(path.to_string());
+                                }
+                            }
+                        }
+                        Some(_) => {
+                            return Event::Invalid {
+                                id: msg.id,
+                                error: "paths must be a non-empty array".to_string(),
+                            }
+                        }
+                    }
+                    let mut paths = paths.into_iter();
+                    let mirrors = match data.get("mirrors") {
+                        None => Vec::new(),
+                        Some(Value::Array(list)) => {
+                            match list.iter().map(Value::as_str).collect::<Option<Vec<_>>>() {
+                                Some(list) => list.into_iter().map(str::to_string).collect(),
+                                None => {
+                                    return Event::Invalid {
+                                        id: msg.id,
+                                        error: "mirrors must only contain strings".to_string(),
+                                    }
+                                }
+                            }
+                        }
+                        Some(_) => {
+                            return Event::Invalid {
+                                id: msg.id,
+                                error: "mirrors must be an array of URLs".to_string(),
+                            }
+                        }
+                    };
                     if let Some(url) = json_str(data, "url") {
-                        if let Some(path) = json_str(data, "path") {
-                            return Event::Download(FileDownloadUploadTask {
+                        if let Some(path) = paths.next() {
+                            let decompress = match json_str(data, "decompress") {
+                                Some(name) => match Compression::parse(&name) {
+                                    Some(compression) => Some(compression),
+                                    None => {
+                                        return Event::Invalid {
+                                            id: msg.id,
+                                            error: format!(
+                                                "Unsupported decompress value {}, expected gzip or zstd",
+                                                name
+                                            ),
+                                        }
+                                    }
+                                },
+                                None => None,
+                            };
+                            let headers = match json_str_map(data, "headers") {
+                                Ok(headers) => headers,
+                                Err(error) => return Event::Invalid { id: msg.id, error },
+                            };
+                            return Event::Download(FileDownloadTask {
                                 id: msg.id,
                                 url,
                                 path,
+                                sha256: json_str(data, "sha256"),
+                                resume: json_bool(data, "resume").unwrap_or(false),
+                                decompress,
+                                max_bytes: json_int(data, "max_bytes")
+                                    .and_then(|v| u64::try_from(v).ok()),
+                                headers,
+                                mode: json_str(data, "mode"),
+                                copies: paths.collect(),
+                                mirrors,
+                                post_hook: json_str(data, "post_hook"),
+                                verify_signature: json_bool(data, "verify_signature")
+                                    .unwrap_or(false),
+                                signature: json_str(data, "signature"),
+                                signature_url: json_str(data, "signature_url"),
                             });
                         }
                     }
@@ -91,20 +617,172 @@ impl From<EventMessage> for Event {
                 if let Some(data) = msg.data.as_ref() {
                     if let Some(url) = json_str(data, "url") {
                         if let Some(path) = json_str(data, "path") {
-                            return Event::Upload(FileDownloadUploadTask {
+                            let headers = match json_str_map(data, "headers") {
+                                Ok(headers) => headers,
+                                Err(error) => return Event::Invalid { id: msg.id, error },
+                            };
+                            return Event::Upload(FileUploadTask {
                                 id: msg.id,
                                 url,
                                 path,
+                                multipart: json_bool(data, "multipart").unwrap_or(false),
+                                field_name: json_str(data, "field_name"),
+                                headers,
+                                tail: json_bool(data, "tail").unwrap_or(false),
+                                follow_secs: json_int(data, "follow_secs")
+                                    .and_then(|v| u64::try_from(v).ok()),
                             });
                         }
                     }
                 }
                 Event::Raw(msg)
             }
-            "execute" => {
+            "execute" | "execute_stream" => {
+                let Some(data) = msg.data.as_ref() else {
+                    return Event::Raw(msg);
+                };
+                let mut task = match ExecuteTask::from_data(msg.id, data) {
+                    Ok(Some(task)) => task,
+                    Ok(None) => return Event::Raw(msg),
+                    Err(error) => return Event::Invalid { id: msg.id, error },
+                };
+                if msg.event == "execute_stream" {
+                    return Event::ExecuteStream(task);
+                }
+                match Precondition::from_data(data) {
+                    Ok(condition) => task.condition = condition,
+                    Err(error) => return Event::Invalid { id: msg.id, error },
+                }
+                Event::Execute(task)
+            }
+            "execute_argv" => {
+                let Some(data) = msg.data.as_ref() else {
+                    return Event::Raw(msg);
+                };
+                let mut task = match ExecuteTask::from_data(msg.id, data) {
+                    Ok(Some(task)) => task,
+                    Ok(None) => return Event::Raw(msg),
+                    Err(error) => return Event::Invalid { id: msg.id, error },
+                };
+                let args = match data.get("args") {
+                    None => Vec::new(),
+                    Some(Value::Array(args)) => {
+                        match args.iter().map(Value::as_str).collect::<Option<Vec<_>>>() {
+                            Some(args) => args.into_iter().map(str::to_string).collect(),
+                            None => {
+                                return Event::Invalid {
+                                    id: msg.id,
+                                    error: "args must only contain strings".to_string(),
+                                }
+                            }
+                        }
+                    }
+                    Some(_) => {
+                        return Event::Invalid {
+                            id: msg.id,
+                            error: "args must be an array of strings".to_string(),
+                        }
+                    }
+                };
+                task.args = Some(args);
+                match Precondition::from_data(data) {
+                    Ok(condition) => task.condition = condition,
+                    Err(error) => return Event::Invalid { id: msg.id, error },
+                }
+                Event::ExecuteArgv(task)
+            }
+            "upload_stream" => {
+                if let Some(data) = msg.data.as_ref() {
+                    if let Some(url) = json_str(data, "url") {
+                        match ExecuteTask::from_data(msg.id, data) {
+                            Ok(Some(exec)) => {
+                                return Event::UploadStream(UploadStreamTask { url, exec })
+                            }
+                            Ok(None) => {}
+                            Err(error) => return Event::Invalid { id: msg.id, error },
+                        }
+                    }
+                }
+                Event::Raw(msg)
+            }
+            "self_update" => {
+                if let Some(data) = msg.data.as_ref() {
+                    if let (Some(url), Some(sha256)) =
+                        (json_str(data, "url"), json_str(data, "sha256"))
+                    {
+                        return Event::SelfUpdate(SelfUpdateTask {
+                            id: msg.id,
+                            url,
+                            sha256,
+                        });
+                    }
+                }
+                Event::Raw(msg)
+            }
+            "fs_move" | "fs_copy" => {
+                if let Some(data) = msg.data.as_ref() {
+                    if let (Some(src), Some(dst)) = (json_str(data, "src"), json_str(data, "dst")) {
+                        let id = msg.id;
+                        return if msg.event == "fs_move" {
+                            Event::FsMove { id, src, dst }
+                        } else {
+                            Event::FsCopy { id, src, dst }
+                        };
+                    }
+                }
+                Event::Raw(msg)
+            }
+            "fs_delete" => {
                 if let Some(data) = msg.data.as_ref() {
-                    if let Some(cmd) = json_str(data, "cmd") {
-                        return Event::Execute(ExecuteTask { id: msg.id, cmd });
+                    if let Some(path) = json_str(data, "path") {
+                        return Event::FsDelete {
+                            id: msg.id,
+                            path,
+                            recursive: json_bool(data, "recursive").unwrap_or(false),
+                        };
+                    }
+                }
+                Event::Raw(msg)
+            }
+            "read_file" => {
+                if let Some(data) = msg.data.as_ref() {
+                    if let Some(path) = json_str(data, "path") {
+                        return Event::ReadFile {
+                            id: msg.id,
+                            path,
+                            max_bytes: json_int(data, "max_bytes")
+                                .and_then(|v| u64::try_from(v).ok()),
+                        };
+                    }
+                }
+                Event::Raw(msg)
+            }
+            "query_packages" => Event::QueryPackages(msg.id),
+            "verify_files" => {
+                let files = msg
+                    .data
+                    .as_ref()
+                    .and_then(|data| data.get("files"))
+                    .cloned();
+                match files.map(serde_json::from_value::<Vec<FileCheck>>) {
+                    Some(Ok(files)) => Event::VerifyFiles { id: msg.id, files },
+                    Some(Err(err)) => Event::Invalid {
+                        id: msg.id,
+                        error: format!("files must be an array of path and sha256: {}", err),
+                    },
+                    None => Event::Raw(msg),
+                }
+            }
+            "ping" | "status" => Event::Status(msg.id),
+            "echo" => Event::Echo {
+                id: msg.id,
+                data: msg.data,
+            },
+            "url_refresh_response" => Event::Reply(msg),
+            "cancel" => {
+                if let Some(data) = msg.data.as_ref() {
+                    if let Some(target) = json_int(data, "id").and_then(|v| u64::try_from(v).ok()) {
+                        return Event::Cancel { id: msg.id, target };
                     }
                 }
                 Event::Raw(msg)
@@ -114,156 +792,1700 @@ impl From<EventMessage> for Event {
     }
 }
 
+/// Outgoing websocket frames, forwarded to controller by the connection writer task.
+type Outbox = mpsc::UnboundedSender<Message>;
+
+/// Tell controller that a task was accepted and its work begins.
+fn send_task_started(tx: &Outbox, id: u64, kind: &'static str) -> Result<()> {
+    metrics::task_started(kind);
+    let started = EventMessage {
+        id,
+        event: "task_started".to_string(),
+        code: TaskResultCode::Success.into(),
+        idempotency_key: None,
+        data: Some(hashmap! {
+            "type".to_string() => Value::String(kind.to_string())
+        }),
+    };
+    tx.send(Message::Text(json!(started).to_string()))?;
+    Ok(())
+}
+
+/// Send the final reply of a task, counting it in the metrics.
+///
+/// That's the `task_completed` of a task that was started or answered by a dry run, or the
+/// `task_rejected` of one refused by the allowlists.
+///
+/// Replies of tasks with an idempotency key carry the key and are kept to answer redeliveries
+/// of the task, unless the task was cancelled.
+fn send_task_completed(
+    tx: &Outbox,
+    state: &AgentState,
+    kind: &'static str,
+    mut response: EventMessage,
+) -> Result<()> {
+    if response.code == i32::from(TaskResultCode::Cancelled) {
+        state.forget_keyed(response.id);
+    } else {
+        state.complete_keyed(&mut response);
+    }
+    let failed = ![TaskResultCode::Success, TaskResultCode::Skipped]
+        .into_iter()
+        .any(|code| response.code == i32::from(code));
+    metrics::task_completed(kind, failed);
+    tx.send(Message::Text(json!(response).to_string()))?;
+    Ok(())
+}
+
+/// Reply telling controller that the task `id` was not started, with the matching `code`.
+fn task_rejected(id: u64, code: TaskResultCode, error: &str) -> EventMessage {
+    EventMessage {
+        id,
+        event: "task_rejected".to_string(),
+        code: code.into(),
+        idempotency_key: None,
+        data: Some(hashmap! {
+            "error".to_string() => Value::String(error.to_string())
+        }),
+    }
+}
+
+/// Tell controller that a task was not started, with the matching result `code`.
+fn send_task_rejected(tx: &Outbox, id: u64, code: TaskResultCode, error: &str) -> Result<()> {
+    tx.send(Message::Text(
+        json!(task_rejected(id, code, error)).to_string(),
+    ))?;
+    Ok(())
+}
+
+/// Build the `task_completed` reply of an execute task.
+///
+/// Exit codes are passed through unchanged. Commands killed by a signal report `128 + signal`
+/// like shells do, with the signal number in `data.signal`. Commands that could not be spawned
+/// or failed with an I/O error report `TaskResultCode::ExecFailed` and timeouts
+/// `TaskResultCode::Timeout`.
+fn execute_completed(
+    id: u64,
+    result: Result<CommandExit>,
+    mut data: HashMap<String, Value>,
+) -> EventMessage {
+    match result {
+        Ok(exit) => {
+            if let Some(signal) = exit.signal() {
+                data.insert("signal".to_string(), json!(signal));
+            }
+            EventMessage {
+                id,
+                event: "task_completed".to_string(),
+                code: exit.code(),
+                idempotency_key: None,
+                data: (!data.is_empty()).then_some(data),
+            }
+        }
+        Err(err) => EventMessage {
+            id,
+            event: "task_completed".to_string(),
+            code: if err.is::<CommandTimeout>() {
+                TaskResultCode::Timeout
+            } else {
+                TaskResultCode::ExecFailed
+            }
+            .into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "error".to_string() => Value::String(err.to_string())
+            }),
+        },
+    }
+}
+
+/// Build the `task_completed` reply of a filesystem, `read_file`, `query_packages`,
+/// `verify_files` or registered task.
+fn fs_completed(id: u64, result: Result<HashMap<String, Value>>) -> EventMessage {
+    match result {
+        Ok(data) => EventMessage {
+            id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::Success.into(),
+            idempotency_key: None,
+            data: (!data.is_empty()).then_some(data),
+        },
+        Err(err) => EventMessage {
+            id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::Failed.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "error".to_string() => Value::String(err.to_string())
+            }),
+        },
+    }
+}
+
+/// Copy the file a download task wrote to its further destinations, reporting the ones
+/// written and the ones that failed. `skipped` tells that `path` already held the file.
+async fn copy_download_result(task: &FileDownloadTask, skipped: bool) -> EventMessage {
+    let mut written = vec![Value::String(task.path.clone())];
+    let mut failed = serde_json::Map::new();
+    for dst in &task.copies {
+        match utils::copy_download(&task.path, dst).await {
+            Ok(()) => written.push(Value::String(dst.clone())),
+            Err(err) => {
+                error!("Failed to copy {} to {}: {}", task.path, dst, err);
+                failed.insert(dst.clone(), Value::String(err.to_string()));
+            }
+        }
+    }
+    let total = task.copies.len() + 1;
+    let mut data = hashmap! {
+        "paths".to_string() => Value::Array(written),
+    };
+    if skipped {
+        data.insert("skipped".to_string(), Value::Bool(true));
+    }
+    if !failed.is_empty() {
+        data.insert(
+            "error".to_string(),
+            Value::String(format!(
+                "failed to write {} of {} destinations",
+                failed.len(),
+                total
+            )),
+        );
+        data.insert("failed".to_string(), Value::Object(failed));
+    }
+    EventMessage {
+        id: task.id,
+        event: "task_completed".to_string(),
+        code: if data.contains_key("failed") {
+            TaskResultCode::Failed
+        } else {
+            TaskResultCode::Success
+        }
+        .into(),
+        idempotency_key: None,
+        data: Some(data),
+    }
+}
+
+/// Run the `post_hook` of a download task unless there's a reason not to, describing whether
+/// it ran and how it ended for the reply of the task.
+async fn run_post_hook(hook: &str, not_run: Option<&str>, config: &config::Config) -> Value {
+    if let Some(reason) = not_run {
+        info!("Post hook not run: {}", reason);
+        return json!({ "ran": false, "reason": reason });
+    }
+    info!("Running post hook: {}", hook);
+    let (program, args) = Shell::default().command(hook);
+    let options = CommandOptions {
+        timeout: config.exec_timeout_secs.map(Duration::from_secs),
+        ..Default::default()
+    };
+    match execute_command_with_output(&program, args, options, config.max_output_bytes).await {
+        Ok(output) => {
+            if output.exit != CommandExit::Code(0) {
+                warn!("Post hook exited with code {}", output.exit.code());
+            }
+            json!({
+                "ran": true,
+                "exit_code": output.exit.code(),
+                "signal": output.exit.signal(),
+                "output": output.output,
+                "truncated": output.truncated,
+            })
+        }
+        Err(err) => {
+            warn!("Post hook failed: {}", err);
+            json!({
+                "ran": true,
+                "error": err.to_string(),
+                "timed_out": err.is::<CommandTimeout>(),
+            })
+        }
+    }
+}
+
+/// Check the signature of the file a download task wrote, removing the file unless it's
+/// signed by `signing_public_key`.
+async fn verify_download_signature(
+    client: &reqwest::Client,
+    config: &config::Config,
+    task: &FileDownloadTask,
+) -> Result<()> {
+    let result = async {
+        let key = config
+            .signing_public_key
+            .as_deref()
+            .ok_or_else(|| anyhow!("Signature verification requires signing_public_key"))?;
+        let key = utils::parse_public_key(key)?;
+        let signature = match (&task.signature, &task.signature_url) {
+            (Some(signature), _) => utils::decode_signature(signature.as_bytes())?,
+            (None, Some(url)) => utils::fetch_signature(client, url, task.headers.as_ref()).await?,
+            (None, None) => bail!("Task has neither signature nor signature_url"),
+        };
+        utils::verify_file_signature(&task.path, &signature, &key).await
+    }
+    .await;
+    match &result {
+        Ok(()) => info!("Verified signature of {}: id = {}", task.path, task.id),
+        Err(err) => {
+            error!(
+                "Failed to verify signature of {}: {}: id = {}",
+                task.path, err, task.id
+            );
+            if let Err(err) = tokio::fs::remove_file(&task.path).await {
+                warn!("Failed to remove unverified file {}: {}", task.path, err);
+            }
+        }
+    }
+    result
+}
+
+/// Task aborted when the connection owning it goes away, however the connection ends.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Send queued log records to controller as `agent_log` events.
+///
+/// With several connections only one forwards at a time, the others take over once it ends.
+/// Nothing is logged here, a record about forwarding a record would be forwarded in turn.
+async fn forward_logs(state: Arc<AgentState>, tx: Outbox) {
+    let Some(records) = state.log_records.as_ref() else {
+        return;
+    };
+    let mut records = records.lock().await;
+    while let Some(record) = records.recv().await {
+        let event = EventMessage {
+            id: 0,
+            event: "agent_log".to_string(),
+            code: TaskResultCode::Success.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "level".to_string() => Value::String(record.level.to_string()),
+                "target".to_string() => Value::String(record.target),
+                "message".to_string() => Value::String(record.message),
+                "time".to_string() => json!(record.time),
+                "task_id".to_string() => json!(record.task_id),
+            }),
+        };
+        if tx.send(Message::Text(json!(event).to_string())).is_err() {
+            return;
+        }
+    }
+}
+
+/// Report transfer progress of the task `id` as `task_progress` events, at most once a second.
+fn progress_reporter(tx: &Outbox, id: u64) -> ProgressCallback {
+    let progress_tx = tx.clone();
+    let mut last_progress: Option<Instant> = None;
+    Box::new(move |bytes: u64, total: Option<u64>| {
+        if last_progress.is_some_and(|t| t.elapsed() < Duration::from_secs(1)) {
+            return;
+        }
+        last_progress = Some(Instant::now());
+        let progress = EventMessage {
+            id,
+            event: "task_progress".to_string(),
+            code: TaskResultCode::Success.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "bytes".to_string() => json!(bytes),
+                "total".to_string() => json!(total),
+            }),
+        };
+        _ = progress_tx.send(Message::Text(json!(progress).to_string()));
+    })
+}
+
+/// Whether a transfer failed because the server refused its URL.
+fn is_auth_failure(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<HttpStatusError>().is_some_and(|err| {
+        err.status == StatusCode::UNAUTHORIZED || err.status == StatusCode::FORBIDDEN
+    })
+}
+
+/// Ask controller for a fresh URL of the download task `id` through a `url_refresh_request`,
+/// and wait up to `timeout` for the `url_refresh_response` carrying it.
+async fn request_url_refresh(
+    tx: &Outbox,
+    state: &AgentState,
+    id: u64,
+    url: &str,
+    timeout: Duration,
+) -> Result<String> {
+    // Wait before asking so a quick answer can't be missed
+    let reply = state.await_reply(id);
+    let request = EventMessage {
+        id,
+        event: "url_refresh_request".to_string(),
+        code: TaskResultCode::Success.into(),
+        idempotency_key: None,
+        data: Some(hashmap! {
+            "url".to_string() => Value::String(url.to_string())
+        }),
+    };
+    tx.send(Message::Text(json!(request).to_string()))?;
+    let response = match tokio::time::timeout(timeout, reply).await {
+        Ok(response) => response?,
+        Err(_) => {
+            state.forget_reply(id);
+            bail!("Timed out waiting for url_refresh_response");
+        }
+    };
+    if response.code != i32::from(TaskResultCode::Success) {
+        bail!(
+            "Controller refused to refresh URL: code = {}",
+            response.code
+        );
+    }
+    response
+        .data
+        .as_ref()
+        .and_then(|data| json_str(data, "url"))
+        .ok_or_else(|| anyhow!("url_refresh_response without url"))
+}
+
+/// Download the binary of a `self_update` task next to the running executable and swap it in,
+/// returning the path of the executable to restart.
+///
+/// The download is verified against the task checksum before anything is replaced.
+async fn self_update(
+    client: &reqwest::Client,
+    config: &config::Config,
+    task: &SelfUpdateTask,
+) -> Result<std::path::PathBuf> {
+    let exe = std::env::current_exe()?;
+    // Same directory, so renaming over the executable stays on one filesystem. The task id
+    // keeps concurrent updates from writing to the same file.
+    let new_path = format!("{}.update.{}", exe.display(), task.id);
+    download_file(
+        client,
+        task.url.as_str(),
+        new_path.as_str(),
+        DownloadOptions {
+            sha256: Some(task.sha256.as_str()),
+            retry: config.transfer_retry(),
+            read_timeout: config.read_timeout(),
+            max_bytes_per_sec: config.max_download_bytes_per_sec,
+            max_bytes: config.max_download_bytes,
+            buffer_bytes: config.download_buffer_bytes,
+            ..Default::default()
+        },
+        None,
+    )
+    .await?;
+    if let Err(err) = utils::replace_executable(&new_path, &exe) {
+        utils::remove_partial_file(&new_path);
+        return Err(err);
+    }
+    info!("Replaced {} with binary from {}", exe.display(), task.url);
+    Ok(exe)
+}
+
+/// Start the updated executable in place of this process.
+///
+/// When exec is not possible the process exits with a non-zero code instead, leaving the
+/// restart to a supervisor such as systemd with `Restart=on-failure`. Running tasks are
+/// abandoned either way.
+fn restart(exe: &std::path::Path) -> ! {
+    #[cfg(unix)]
+    {
+        info!("Restarting {}", exe.display());
+        let err = utils::reexec(exe);
+        error!("Failed to restart {}: {}, exit instead", exe.display(), err);
+    }
+    #[cfg(not(unix))]
+    info!("Exiting to have {} restarted", exe.display());
+    std::process::exit(1)
+}
+
+/// Fail a task given to the handler of another event type, such as one registered under the
+/// wrong name.
+fn fail_unexpected_event(event: Event, context: &TaskContext, handler: &str) -> Result<()> {
+    let (id, kind) = (event.task_id().unwrap_or_default(), event.kind());
+    error!(
+        "Handler of {} events got a {} event: id = {}",
+        handler, kind, id
+    );
+    let response = EventMessage {
+        id,
+        event: "task_completed".to_string(),
+        code: TaskResultCode::UnknownEvent.into(),
+        idempotency_key: None,
+        data: Some(hashmap! {
+            "error".to_string() => Value::String(format!("no handler for {} events", kind))
+        }),
+    };
+    send_task_completed(&context.tx, &context.state, kind, response)
+}
+
+async fn handle_download(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx,
+        client,
+        config,
+        state,
+    } = &context;
+    let kind = event.kind();
+    let Event::Download(task) = event else {
+        return fail_unexpected_event(event, &context, "download");
+    };
+    info!("Task download begin: id = {}", task.id);
+    send_task_started(tx, task.id, "download")?;
+    let options = DownloadOptions {
+        sha256: task.sha256.as_deref(),
+        resume: task.resume,
+        retry: config.transfer_retry(),
+        read_timeout: config.read_timeout(),
+        max_bytes_per_sec: config.max_download_bytes_per_sec,
+        decompress: task.decompress,
+        // The stricter of both limits applies
+        max_bytes: match (task.max_bytes, config.max_download_bytes) {
+            (Some(task_max), Some(config_max)) => Some(task_max.min(config_max)),
+            (task_max, config_max) => task_max.or(config_max),
+        },
+        headers: task.headers.as_ref(),
+        mode: task.mode.as_deref(),
+        buffer_bytes: config.download_buffer_bytes,
+        temp_dir: config.temp_dir.as_deref(),
+    };
+    let mut url = task.url.clone();
+    let mut refreshed = false;
+    let result = loop {
+        let progress = Some(progress_reporter(tx, task.id));
+        let result = if task.mirrors.is_empty() {
+            download_file(client, &url, &task.path, options.clone(), progress).await
+        } else {
+            mirror::download_from_mirrors(
+                client,
+                &url,
+                &task.mirrors,
+                &task.path,
+                options.clone(),
+                progress,
+            )
+            .await
+        };
+        // Pre-signed URLs may have expired while the task was queued. A fresh URL is
+        // asked for only once, so a controller handing out bad URLs can't cause a loop.
+        match result {
+            Err(err) if !refreshed && is_auth_failure(&err) => {
+                refreshed = true;
+                warn!("{}, ask controller for a fresh URL: id = {}", err, task.id);
+                let timeout = Duration::from_secs(config.url_refresh_timeout_secs);
+                match request_url_refresh(tx, state, task.id, &url, timeout).await {
+                    Ok(fresh) => url = fresh,
+                    Err(refresh_err) => {
+                        warn!("Failed to refresh URL: {}: id = {}", refresh_err, task.id);
+                        break Err(err);
+                    }
+                }
+            }
+            result => break result,
+        }
+    };
+    let result = match result {
+        Ok(outcome) if task.verify_signature => verify_download_signature(client, config, &task)
+            .await
+            .map(|()| outcome),
+        result => result,
+    };
+    let outcome = result.as_ref().ok().copied();
+    let mut response = if let (Some(outcome), false) = (outcome, task.copies.is_empty()) {
+        // The file was verified at `path` already, copies are only written
+        copy_download_result(&task, outcome == DownloadOutcome::Skipped).await
+    } else {
+        EventMessage {
+            id: task.id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::of(&result).into(),
+            idempotency_key: None,
+            data: result.map_or_else(
+                |err| {
+                    let reason = if err.is::<ChecksumMismatch>() {
+                        "checksum mismatch".to_string()
+                    } else if err.is::<UnauthorizedArtifact>() {
+                        "unauthorized artifact".to_string()
+                    } else if let Some(err) = err.downcast_ref::<InsufficientDiskSpace>() {
+                        format!(
+                            "insufficient disk space: {} bytes required, {} bytes available",
+                            err.required, err.available
+                        )
+                    } else {
+                        format!("download failed: {}", err)
+                    };
+                    Some(hashmap! {
+                        "error".to_string() => Value::String(reason)
+                    })
+                },
+                |outcome| {
+                    (outcome == DownloadOutcome::Skipped).then(|| {
+                        hashmap! {
+                            "skipped".to_string() => Value::Bool(true)
+                        }
+                    })
+                },
+            ),
+        }
+    };
+    if let Some(hook) = &task.post_hook {
+        // A failed copy leaves the download incomplete too
+        let not_run = if response.code != i32::from(TaskResultCode::Success) {
+            Some("download failed")
+        } else if outcome == Some(DownloadOutcome::Skipped) {
+            Some("file unchanged")
+        } else {
+            None
+        };
+        let hook = run_post_hook(hook, not_run, config).await;
+        response
+            .data
+            .get_or_insert_with(HashMap::new)
+            .insert("post_hook".to_string(), hook);
+    }
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task download completed: id = {}", task.id);
+    Ok(())
+}
+
+async fn handle_upload(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx,
+        client,
+        config,
+        state,
+    } = &context;
+    let kind = event.kind();
+    let Event::Upload(task) = event else {
+        return fail_unexpected_event(event, &context, "upload");
+    };
+    info!("Task upload begin: id = {}", task.id);
+    send_task_started(tx, task.id, "upload")?;
+    let result = upload_file(
+        client,
+        task.url.as_str(),
+        task.path.as_str(),
+        UploadOptions {
+            multipart: task.multipart,
+            field_name: task.field_name.as_deref(),
+            retry: config.transfer_retry(),
+            headers: task.headers.as_ref(),
+            tail: task.tail,
+            follow: task.follow_secs.map(Duration::from_secs),
+        },
+        Some(progress_reporter(tx, task.id)),
+    )
+    .await;
+    let response = match result {
+        Ok(summary) => EventMessage {
+            id: task.id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::Success.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "sha256".to_string() => Value::String(summary.sha256),
+                "size".to_string() => json!(summary.size),
+            }),
+        },
+        Err(err) => EventMessage {
+            id: task.id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::Failed.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "error".to_string() => Value::String(format!("upload failed: {}", err))
+            }),
+        },
+    };
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task upload completed: id = {}", task.id);
+    Ok(())
+}
+
+async fn handle_execute(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx, config, state, ..
+    } = &context;
+    let kind = event.kind();
+    let (Event::Execute(task) | Event::ExecuteArgv(task)) = event else {
+        return fail_unexpected_event(event, &context, "execute");
+    };
+    info!("Task {} begin: id = {}", kind, task.id);
+    send_task_started(tx, task.id, kind)?;
+    if let Some(reason) = match &task.condition {
+        Some(condition) => condition.unmet().await,
+        None => None,
+    } {
+        info!("Task {} skipped, {}: id = {}", kind, reason, task.id);
+        let response = EventMessage {
+            id: task.id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::Skipped.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "skipped".to_string() => Value::Bool(true),
+                "reason".to_string() => Value::String(reason),
+            }),
+        };
+        send_task_completed(tx, state, kind, response)?;
+        return Ok(());
+    }
+    let options = task.command_options(config);
+    let (result, data) = match task.command() {
+        Ok((program, args)) if task.capture_output && task.split_output => {
+            let result =
+                execute_command_with_split_output(&program, args, options, config.max_output_bytes)
+                    .await;
+            match result {
+                Ok(output) => (
+                    Ok(output.exit),
+                    hashmap! {
+                        "stdout".to_string() => Value::String(output.stdout),
+                        "stderr".to_string() => Value::String(output.stderr),
+                        "truncated".to_string() => Value::Bool(output.truncated),
+                    },
+                ),
+                Err(err) => (Err(err), HashMap::new()),
+            }
+        }
+        Ok((program, args)) if task.capture_output => {
+            let result =
+                execute_command_with_output(&program, args, options, config.max_output_bytes).await;
+            match result {
+                Ok(output) => (
+                    Ok(output.exit),
+                    hashmap! {
+                        "output".to_string() => Value::String(output.output),
+                        "truncated".to_string() => Value::Bool(output.truncated),
+                    },
+                ),
+                Err(err) => (Err(err), HashMap::new()),
+            }
+        }
+        Ok((program, args)) => (
+            execute_command(&program, args, options).await,
+            HashMap::new(),
+        ),
+        Err(err) => (Err(err), HashMap::new()),
+    };
+    let response = execute_completed(task.id, result, data);
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task {} completed: id = {}", kind, task.id);
+    Ok(())
+}
+
+async fn handle_execute_stream(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx, config, state, ..
+    } = &context;
+    let kind = event.kind();
+    let Event::ExecuteStream(task) = event else {
+        return fail_unexpected_event(event, &context, "execute_stream");
+    };
+    info!("Task execute_stream begin: id = {}", task.id);
+    send_task_started(tx, task.id, "execute_stream")?;
+    // Lines are queued to the outbox so the output reader never waits on the socket
+    let output_tx = tx.clone();
+    let output = Box::new(move |stream: OutputStream, line: String| {
+        let output = EventMessage {
+            id: task.id,
+            event: "task_output".to_string(),
+            code: TaskResultCode::Success.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "stream".to_string() => Value::String(stream.as_str().to_string()),
+                "line".to_string() => Value::String(line),
+            }),
+        };
+        _ = output_tx.send(Message::Text(json!(output).to_string()));
+    });
+    let result = match task.command() {
+        Ok((program, args)) => {
+            execute_command_with_callback(&program, args, task.command_options(config), output)
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    let response = execute_completed(task.id, result, HashMap::new());
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task execute_stream completed: id = {}", task.id);
+    Ok(())
+}
+
+async fn handle_upload_stream(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx,
+        client,
+        config,
+        state,
+    } = &context;
+    let kind = event.kind();
+    let Event::UploadStream(task) = event else {
+        return fail_unexpected_event(event, &context, "upload_stream");
+    };
+    let id = task.exec.id;
+    info!("Task upload_stream begin: id = {}", id);
+    send_task_started(tx, id, "upload_stream")?;
+    let result = match task.exec.command() {
+        Ok((program, args)) => {
+            upload_command_output(
+                client,
+                &task.url,
+                &program,
+                args,
+                task.exec.command_options(config),
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+    // A failed upload is a transfer failure, otherwise the exit code of the command counts
+    let response = match result {
+        Ok((exit, upload)) => {
+            let mut data = hashmap! {
+                "exit_code".to_string() => json!(exit.code()),
+            };
+            if let Some(signal) = exit.signal() {
+                data.insert("signal".to_string(), json!(signal));
+            }
+            let code = match upload {
+                Ok(summary) => {
+                    data.insert("sha256".to_string(), Value::String(summary.sha256));
+                    data.insert("size".to_string(), json!(summary.size));
+                    exit.code()
+                }
+                Err(err) => {
+                    data.insert(
+                        "error".to_string(),
+                        Value::String(format!("upload failed: {}", err)),
+                    );
+                    TaskResultCode::Failed.into()
+                }
+            };
+            EventMessage {
+                id,
+                event: "task_completed".to_string(),
+                code,
+                idempotency_key: None,
+                data: Some(data),
+            }
+        }
+        Err(err) => execute_completed(id, Err(err), HashMap::new()),
+    };
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task upload_stream completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_self_update(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx,
+        client,
+        config,
+        state,
+    } = &context;
+    let kind = event.kind();
+    let Event::SelfUpdate(task) = event else {
+        return fail_unexpected_event(event, &context, "self_update");
+    };
+    info!("Task self_update begin: id = {}", task.id);
+    send_task_started(tx, task.id, "self_update")?;
+    let result = self_update(client, config, &task).await;
+    let response = EventMessage {
+        id: task.id,
+        event: "task_completed".to_string(),
+        code: TaskResultCode::of(&result).into(),
+        idempotency_key: None,
+        data: Some(match &result {
+            Ok(_) => hashmap! {
+                "restarting".to_string() => Value::Bool(true)
+            },
+            Err(err) => hashmap! {
+                "error".to_string() => Value::String(format!("self update failed: {:#}", err))
+            },
+        }),
+    };
+    send_task_completed(tx, state, kind, response)?;
+    info!("Task self_update completed: id = {}", task.id);
+    if let Ok(exe) = result {
+        // Give the connection writer a moment to deliver the reply
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // The restart ends this task, it must not look interrupted to the next run
+        state.journal(task.id, "self_update", TaskStatus::Completed);
+        restart(&exe);
+    }
+    Ok(())
+}
+
+async fn handle_fs_move(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext { tx, state, .. } = &context;
+    let kind = event.kind();
+    let Event::FsMove { id, src, dst } = event else {
+        return fail_unexpected_event(event, &context, "fs_move");
+    };
+    info!("Task fs_move begin: id = {}", id);
+    send_task_started(tx, id, "fs_move")?;
+    let result = utils::move_path(&src, &dst).await.map(|_| HashMap::new());
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task fs_move completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_fs_copy(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext { tx, state, .. } = &context;
+    let kind = event.kind();
+    let Event::FsCopy { id, src, dst } = event else {
+        return fail_unexpected_event(event, &context, "fs_copy");
+    };
+    info!("Task fs_copy begin: id = {}", id);
+    send_task_started(tx, id, "fs_copy")?;
+    let result = utils::copy_file(&src, &dst).await.map(|bytes| {
+        hashmap! {
+            "bytes".to_string() => json!(bytes)
+        }
+    });
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task fs_copy completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_fs_delete(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext { tx, state, .. } = &context;
+    let kind = event.kind();
+    let Event::FsDelete {
+        id,
+        path,
+        recursive,
+    } = event
+    else {
+        return fail_unexpected_event(event, &context, "fs_delete");
+    };
+    info!("Task fs_delete begin: id = {}", id);
+    send_task_started(tx, id, "fs_delete")?;
+    let result = utils::delete_path(&path, recursive)
+        .await
+        .map(|_| HashMap::new());
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task fs_delete completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_read_file(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx, config, state, ..
+    } = &context;
+    let kind = event.kind();
+    let Event::ReadFile {
+        id,
+        path,
+        max_bytes,
+    } = event
+    else {
+        return fail_unexpected_event(event, &context, "read_file");
+    };
+    info!("Task read_file begin: id = {}", id);
+    send_task_started(tx, id, "read_file")?;
+    // A task may ask for less than the configured limit, never for more
+    let max_bytes = max_bytes
+        .unwrap_or(config.max_read_file_bytes)
+        .min(config.max_read_file_bytes);
+    let result = utils::read_file(&path, max_bytes).await.map(|content| {
+        hashmap! {
+            "size".to_string() => json!(content.len()),
+            "content".to_string() => Value::String(STANDARD.encode(content)),
+        }
+    });
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task read_file completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_query_packages(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx, config, state, ..
+    } = &context;
+    let kind = event.kind();
+    let Event::QueryPackages(id) = event else {
+        return fail_unexpected_event(event, &context, "query_packages");
+    };
+    info!("Task query_packages begin: id = {}", id);
+    send_task_started(tx, id, "query_packages")?;
+    let options = CommandOptions {
+        timeout: config.exec_timeout_secs.map(Duration::from_secs),
+        ..Default::default()
+    };
+    let result = packages::query_packages(options, config.max_package_list_bytes)
+        .await
+        .map(|list| {
+            hashmap! {
+                "manager".to_string() => json!(list.manager),
+                "packages".to_string() => json!(list.packages),
+                "truncated".to_string() => json!(list.truncated),
+            }
+        });
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task query_packages completed: id = {}", id);
+    Ok(())
+}
+
+async fn handle_verify_files(event: Event, context: TaskContext) -> Result<()> {
+    let TaskContext {
+        tx, config, state, ..
+    } = &context;
+    let kind = event.kind();
+    let Event::VerifyFiles { id, files } = event else {
+        return fail_unexpected_event(event, &context, "verify_files");
+    };
+    info!("Task verify_files begin: id = {}", id);
+    send_task_started(tx, id, "verify_files")?;
+    let result = if files.len() > config.max_verify_files {
+        Err(anyhow!(
+            "{} files to verify, more than the limit of {}",
+            files.len(),
+            config.max_verify_files
+        ))
+    } else {
+        let results = integrity::verify_files(files, config.max_verify_bytes).await;
+        let count = |state| json!(results.iter().filter(|file| file.state == state).count());
+        Ok(hashmap! {
+            "matched".to_string() => count(FileState::Match),
+            "mismatched".to_string() => count(FileState::Mismatch),
+            "missing".to_string() => count(FileState::Missing),
+            "files".to_string() => json!(results),
+        })
+    };
+    send_task_completed(tx, state, kind, fs_completed(id, result))?;
+    info!("Task verify_files completed: id = {}", id);
+    Ok(())
+}
+
+/// Handlers of the task events of the agent itself.
+fn builtin_handlers() -> HandlerRegistry {
+    fn handler<F, Fut>(handle: F) -> Handler
+    where
+        F: Fn(Event, TaskContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        Arc::new(move |event, context| Box::pin(handle(event, context)))
+    }
+    HandlerRegistry::builtin([
+        ("download", handler(handle_download)),
+        ("upload", handler(handle_upload)),
+        ("execute", handler(handle_execute)),
+        ("execute_argv", handler(handle_execute)),
+        ("execute_stream", handler(handle_execute_stream)),
+        ("upload_stream", handler(handle_upload_stream)),
+        ("self_update", handler(handle_self_update)),
+        ("fs_move", handler(handle_fs_move)),
+        ("fs_copy", handler(handle_fs_copy)),
+        ("fs_delete", handler(handle_fs_delete)),
+        ("read_file", handler(handle_read_file)),
+        ("query_packages", handler(handle_query_packages)),
+        ("verify_files", handler(handle_verify_files)),
+    ])
+}
+
 async fn handle_message(
     event: Event,
-    tx: &mut futures_util::stream::SplitSink<
-        tokio_tungstenite::WebSocketStream<
-            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-        >,
-        Message,
-    >,
+    tx: &Outbox,
     client: &reqwest::Client,
+    config: &Arc<config::Config>,
+    state: &Arc<AgentState>,
 ) -> Result<()> {
+    // Tasks still queued when shutdown begins would only be aborted once the drain times out
+    if let Some(id) = event.task_id().filter(|_| state.shutting_down()) {
+        warn!("Shutting down, reject task: id = {}", id);
+        return send_task_rejected(tx, id, TaskResultCode::ShuttingDown, "shutting down");
+    }
+    let kind = event.kind();
+    if let Event::Execute(task)
+    | Event::ExecuteArgv(task)
+    | Event::ExecuteStream(task)
+    | Event::UploadStream(UploadStreamTask { exec: task, .. }) = &event
+    {
+        if !state.command_allowed(&task.cmd) {
+            warn!("Command not allowed, reject task: id = {}", task.id);
+            let rejected = task_rejected(
+                task.id,
+                TaskResultCode::PermissionDenied,
+                "command not allowed",
+            );
+            return send_task_completed(tx, state, kind, rejected);
+        }
+    }
+    if let Event::Download(FileDownloadTask {
+        id,
+        post_hook: Some(hook),
+        ..
+    }) = &event
+    {
+        if !state.command_allowed(hook) {
+            warn!("Post hook not allowed, reject task: id = {}", id);
+            let rejected = task_rejected(
+                *id,
+                TaskResultCode::PermissionDenied,
+                "post hook not allowed",
+            );
+            return send_task_completed(tx, state, kind, rejected);
+        }
+    }
+    if let Event::SelfUpdate(task) = &event {
+        if !config.allow_self_update {
+            warn!("Self update not allowed, reject task: id = {}", task.id);
+            let rejected = task_rejected(
+                task.id,
+                TaskResultCode::PermissionDenied,
+                "self update not allowed",
+            );
+            return send_task_completed(tx, state, kind, rejected);
+        }
+    }
+    if config.dry_run {
+        let action = match &event {
+            Event::Download(task) => Some((
+                task.id,
+                format!(
+                    "download {} to {}{}",
+                    task.url,
+                    std::iter::once(&task.path)
+                        .chain(&task.copies)
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    task.post_hook
+                        .as_ref()
+                        .map(|hook| format!(" and run {}", hook))
+                        .unwrap_or_default()
+                ),
+            )),
+            Event::Upload(task) => Some((task.id, format!("upload {} to {}", task.path, task.url))),
+            Event::Execute(task) | Event::ExecuteStream(task) => {
+                Some((task.id, format!("execute {}", task.cmd)))
+            }
+            Event::ExecuteArgv(task) => Some((
+                task.id,
+                format!(
+                    "execute {} {:?}",
+                    task.cmd,
+                    task.args.as_deref().unwrap_or_default()
+                ),
+            )),
+            Event::UploadStream(task) => Some((
+                task.exec.id,
+                format!("upload output of {} to {}", task.exec.cmd, task.url),
+            )),
+            Event::SelfUpdate(task) => {
+                Some((task.id, format!("replace agent binary with {}", task.url)))
+            }
+            Event::FsMove { id, src, dst } => Some((*id, format!("move {} to {}", src, dst))),
+            Event::FsCopy { id, src, dst } => Some((*id, format!("copy {} to {}", src, dst))),
+            Event::FsDelete { id, path, .. } => Some((*id, format!("delete {}", path))),
+            Event::QueryPackages(id) => Some((*id, "query installed packages".to_string())),
+            _ => None,
+        };
+        if let Some((id, action)) = action {
+            info!("Dry run, would {}: id = {}", action, id);
+            let response = EventMessage {
+                id,
+                event: "task_completed".to_string(),
+                code: TaskResultCode::Success.into(),
+                idempotency_key: None,
+                data: Some(hashmap! {
+                    "dry_run".to_string() => Value::Bool(true)
+                }),
+            };
+            return send_task_completed(tx, state, kind, response);
+        }
+    }
     match event {
-        Event::Download(task) => {
-            info!("Task download begin: id = {}", task.id);
+        Event::Reply(reply) => {
+            if let Err(reply) = state.deliver_reply(reply) {
+                warn!(
+                    "Received {} nobody is waiting for: id = {}",
+                    reply.event, reply.id
+                );
+            }
+        }
+        Event::Status(id) => {
+            debug!("Reporting agent status: id = {}", id);
             let response = EventMessage {
-                id: task.id,
+                id,
                 event: "task_completed".to_string(),
-                code: if download_file(client, task.url.as_str(), task.path.as_str())
-                    .await
-                    .is_ok()
-                {
-                    0
-                } else {
-                    1
-                },
-                data: None,
+                code: TaskResultCode::Success.into(),
+                idempotency_key: None,
+                data: Some(state.status(config)),
             };
-            tx.send(Message::Text(json!(response).to_string())).await?;
-            info!("Task download completed: id = {}", task.id);
+            tx.send(Message::Text(json!(response).to_string()))?;
         }
-        Event::Upload(task) => {
-            info!("Task upload begin: id = {}", task.id);
+        Event::Echo { id, data } => {
+            debug!("Echoing event data: id = {}", id);
+            let agent_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_millis() as u64)
+                .unwrap_or_default();
             let response = EventMessage {
-                id: task.id,
+                id,
                 event: "task_completed".to_string(),
-                code: if upload_file(client, task.url.as_str(), task.path.as_str())
-                    .await
-                    .is_ok()
-                {
-                    0
-                } else {
-                    1
-                },
-                data: None,
+                code: TaskResultCode::Success.into(),
+                idempotency_key: None,
+                data: Some(hashmap! {
+                    "echo".to_string() => json!(data),
+                    "agent_time_ms".to_string() => json!(agent_time),
+                }),
             };
-            tx.send(Message::Text(json!(response).to_string())).await?;
-            info!("Task upload completed: id = {}", task.id);
+            tx.send(Message::Text(json!(response).to_string()))?;
         }
-        Event::Execute(task) => {
-            info!("Task execute begin: id = {}", task.id);
+        Event::Cancel { id, target } => {
+            let running = state.tasks.lock().unwrap().remove(&target);
+            let response = if let Some(task) = running {
+                info!("Cancelling task: id = {}", target);
+                task.handle.abort();
+                // Wait for the task to actually stop before removing what it left behind
+                _ = task.handle.await;
+                if let Some(path) = task.partial_file {
+                    utils::remove_partial_file(&path);
+                }
+                state.journal(target, task.kind, TaskStatus::Completed);
+                let cancelled = EventMessage {
+                    id: target,
+                    event: "task_completed".to_string(),
+                    code: TaskResultCode::Cancelled.into(),
+                    idempotency_key: None,
+                    data: Some(hashmap! {
+                        "error".to_string() => Value::String("cancelled".to_string())
+                    }),
+                };
+                send_task_completed(tx, state, task.kind, cancelled)?;
+                EventMessage {
+                    id,
+                    event: "task_completed".to_string(),
+                    code: TaskResultCode::Success.into(),
+                    idempotency_key: None,
+                    data: None,
+                }
+            } else {
+                warn!("Task to cancel is not running: id = {}", target);
+                EventMessage {
+                    id,
+                    event: "task_completed".to_string(),
+                    code: TaskResultCode::Failed.into(),
+                    idempotency_key: None,
+                    data: Some(hashmap! {
+                        "error".to_string() => Value::String("Task not running".to_string())
+                    }),
+                }
+            };
+            tx.send(Message::Text(json!(response).to_string()))?;
+        }
+        Event::Invalid { id, error } => {
+            warn!("Received invalid event: {}: id = {}", error, id);
             let response = EventMessage {
-                id: task.id,
+                id,
                 event: "task_completed".to_string(),
-                code: if let Ok(sc) = execute_shell(&task.cmd).await {
-                    sc
-                } else {
-                    -1
-                },
-                data: None,
+                code: TaskResultCode::InvalidMessage.into(),
+                idempotency_key: None,
+                data: Some(hashmap! {
+                    "error".to_string() => Value::String(format!("invalid message: {}", error))
+                }),
             };
-            tx.send(Message::Text(json!(response).to_string())).await?;
-            info!("Task execute completed: id = {}", task.id);
+            tx.send(Message::Text(json!(response).to_string()))?;
         }
         Event::Raw(msg) => {
             warn!("Received unknown event type, ignore");
             let response = EventMessage {
                 id: msg.id,
                 event: "task_completed".to_string(),
-                code: 0x80000000u32 as i32,
+                code: TaskResultCode::UnknownEvent.into(),
+                idempotency_key: None,
                 data: Some(hashmap! {
                     "error".to_string() => Value::String("Unknown event type".to_string())
                 }),
             };
-            tx.send(Message::Text(json!(response).to_string())).await?;
+            tx.send(Message::Text(json!(response).to_string()))?;
+        }
+        task => {
+            let kind = task.kind();
+            let Some(handler) = state.handlers.handler(kind) else {
+                bail!("No handler registered for {} events", kind);
+            };
+            let context = TaskContext {
+                tx: tx.clone(),
+                client: client.clone(),
+                config: config.clone(),
+                state: state.clone(),
+            };
+            handler(task, context).await?;
         }
     }
     Ok(())
 }
 
-async fn agent_main(config: config::Config) -> Result<()> {
-    let api_base_url = format!(
-        "{}://{}:{}/{}",
-        if config.https { "https" } else { "http" },
-        config.addr,
-        config.port,
-        config.api_base_path
+/// Log a frame that isn't a valid event message and reject it, if its `id` can be found.
+fn reject_invalid_message(msg: &str, err: serde_json::Error, tx: &Outbox) -> Result<()> {
+    error!(
+        "Received invalid event message: {}. Payload: {}",
+        err,
+        utils::truncate_str(msg, 256)
     );
-    info!("Use Controller URL: {}", api_base_url);
-    let client = reqwest::Client::new();
+    let id = serde_json::from_str::<Value>(msg)
+        .ok()
+        .and_then(|value| value.get("id").and_then(Value::as_u64));
+    if let Some(id) = id {
+        let response = EventMessage {
+            id,
+            event: "task_completed".to_string(),
+            code: TaskResultCode::InvalidMessage.into(),
+            idempotency_key: None,
+            data: Some(hashmap! {
+                "error".to_string() => Value::String(format!("invalid message: {}", err))
+            }),
+        };
+        tx.send(Message::Text(json!(response).to_string()))?;
+    }
+    Ok(())
+}
+
+/// Spawn tasks onto the runtime so they can run alongside the message loop and be cancelled,
+/// while quick events are handled right away.
+///
+/// Spawned tasks wait for one of `max_concurrent_tasks` slots before doing any work, and can
+/// be cancelled while waiting. Once `max_queued_tasks` are waiting, further tasks are
+/// rejected with `TaskResultCode::Busy` instead of being spawned.
+///
+/// A task whose `idempotency_key` completed recently is answered with its earlier reply, one
+/// whose key is still running is ignored.
+async fn dispatch(
+    event: Event,
+    idempotency_key: Option<String>,
+    tx: &Outbox,
+    client: &reqwest::Client,
+    config: &Arc<config::Config>,
+    state: &Arc<AgentState>,
+) {
+    let Some(id) = event.task_id() else {
+        if let Err(err) = handle_message(event, tx, client, config, state).await {
+            error!("Failed to handle message: {}", err);
+        }
+        return;
+    };
+    let partial_file = event.partial_file(config);
+    let kind = event.kind();
+    // Hold the registry lock while spawning so the task can't deregister before it's registered
+    let mut tasks = state.tasks.lock().unwrap();
+    if state.already_accepted(id) {
+        info!(
+            "Task already delivered by another controller, ignore: id = {}",
+            id
+        );
+        return;
+    }
+    if let Some(key) = &idempotency_key {
+        if let Some(mut reply) = state.completed_reply(key) {
+            drop(tasks);
+            info!(
+                "Task with idempotency key {} completed already, resend its reply: id = {}",
+                key, id
+            );
+            reply.id = id;
+            _ = tx.send(Message::Text(json!(reply).to_string()));
+            return;
+        }
+        if state.key_running(key) {
+            info!(
+                "Task with idempotency key {} is running already, ignore: id = {}",
+                key, id
+            );
+            return;
+        }
+    }
+    if state.shutting_down() {
+        drop(tasks);
+        warn!("Shutting down, reject task: id = {}", id);
+        _ = send_task_rejected(tx, id, TaskResultCode::ShuttingDown, "shutting down");
+        return;
+    }
+    if tasks.len() >= config.max_concurrent_tasks.max(1) + config.max_queued_tasks {
+        drop(tasks);
+        warn!("Too many tasks queued, reject task: id = {}", id);
+        _ = send_task_rejected(tx, id, TaskResultCode::Busy, "agent busy");
+        return;
+    }
+    let (tx, client, config, task_state) =
+        (tx.clone(), client.clone(), config.clone(), state.clone());
+    state.remember_accepted(id);
+    if let Some(key) = idempotency_key {
+        state.start_keyed(id, key);
+    }
+    state.journal(id, kind, TaskStatus::Started);
+    let handle = tokio::spawn(logging::with_task_id(id, async move {
+        // The semaphore is never closed, so acquiring only waits for a free slot
+        if let Ok(_permit) = task_state.task_slots.acquire().await {
+            if let Err(err) = handle_message(event, &tx, &client, &config, &task_state).await {
+                error!("Failed to handle message: {}", err);
+            }
+        }
+        task_state.journal(id, kind, TaskStatus::Completed);
+        task_state.forget_keyed(id);
+        task_state.tasks.lock().unwrap().remove(&id);
+    }));
+    tasks.insert(
+        id,
+        RunningTask {
+            handle,
+            kind,
+            partial_file,
+        },
+    );
+}
+
+/// Wait before reconnecting suggested by controller in the reason of its close frame.
+///
+/// The reason may carry a `retry-after=<seconds>` token, e.g. `overloaded retry-after=60`.
+/// The wait is capped at `backoff_max_secs`.
+fn close_retry_after(frame: &CloseFrame, config: &config::Config) -> Option<Duration> {
+    let secs = frame.reason.split_whitespace().find_map(|token| {
+        let (key, value) = token.split_once('=')?;
+        let key = key.to_ascii_lowercase();
+        (key == "retry-after" || key == "retry_after")
+            .then(|| value.parse::<u64>().ok())
+            .flatten()
+    })?;
+    Some(Duration::from_secs(secs.min(config.backoff_max_secs)))
+}
+
+/// Sleep for `delay`, returning `true` if shutdown was requested in the meantime.
+async fn sleep_or_shutdown(delay: Duration, shutdown: &mut watch::Receiver<bool>) -> bool {
+    select! {
+        _ = tokio::time::sleep(delay) => false,
+        _ = shutdown.wait_for(|v| *v) => true,
+    }
+}
+
+/// Move on from the controller at `current` that just failed, `failed` counting the
+/// controllers that failed in a row.
+///
+/// Returns the backoff delay to wait before trying again once every controller failed, `None`
+/// to try the next one right away.
+fn next_controller(
+    current: &mut usize,
+    failed: &mut usize,
+    controllers: &[ControllerEndpoint],
+    backoff: &mut Backoff,
+) -> Option<Duration> {
+    *current = (*current + 1) % controllers.len();
+    *failed += 1;
+    if *failed < controllers.len() {
+        return None;
+    }
+    *failed = 0;
+    Some(backoff.next_delay())
+}
+
+/// Identity of this agent, sent to controller on registration.
+struct AgentIdentity {
+    machine_uuid: Uuid,
+    system_info: SystemInfo,
+    /// Whether the agent runs as root, `None` on platforms without user ids
+    privileged: Option<bool>,
+    hostname: Option<String>,
+}
+
+/// Register to one of `controllers` and handle its events until an error occurs.
+///
+/// Returns `Ok` only when a shutdown was requested through `shutdown`.
+async fn agent_main(
+    config: config::Config,
+    controllers: &[ControllerEndpoint],
+    identity: &AgentIdentity,
+    backoff: &mut Backoff,
+    state: Arc<AgentState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let config = Arc::new(config);
+    let AgentIdentity {
+        machine_uuid,
+        system_info,
+        privileged,
+        hostname,
+    } = identity;
+    let user_agent = net::user_agent(&config, machine_uuid);
+    let client = net::build_http_client(&config, &user_agent)?;
+    let ws_connector = net::build_ws_connector(&config)?;
+    // Stick to the controller that worked last, moving on to the next one when it fails
+    let mut current = 0;
+    let mut failed = 0;
+    let mut register_failures = 0;
     loop {
-        info!("Trying to connect to controller",);
-        let res = client
-            .post(&format!("{}/register", api_base_url))
+        let controller = &controllers[current];
+        let api_base_url = controller.api_base_url(&config.api_base_path);
+        info!("Trying to connect to controller: {}", api_base_url);
+        let mut request = client
+            .post(format!("{}/{}", api_base_url, config.register_path))
             .json(&serde_json::json!({
-                "clientId": utils::get_machine_uuid()?.to_string(),
-            }))
+                "clientId": machine_uuid.to_string(),
+                "protocol_version": PROTOCOL_VERSION,
+                "system_info": system_info,
+                "privileged": privileged,
+                "hostname": hostname,
+                "display_name": config.display_name,
+                "labels": config.labels,
+            }));
+        if let Some(token) = &config.auth_token {
+            request = request.bearer_auth(token.expose());
+        }
+        let res = request
+            .timeout(Duration::from_secs(config.register_timeout_secs))
             .send()
             .await;
         match res {
             Ok(response) => {
                 debug!("Connected to controller: {:?}", response.status());
-                let client_conf: EventMessage = response.json().await?;
+                let client_conf: EventMessage = match response.json().await {
+                    Ok(client_conf) => client_conf,
+                    Err(err) => {
+                        register_failed(&mut register_failures, &config)?;
+                        let delay = backoff.next_delay();
+                        let reason = if err.is_timeout() {
+                            register_error(&err, &config)
+                        } else {
+                            format!("Invalid registration response from controller: {}", err)
+                        };
+                        error!(
+                            "{}. Retry in {} seconds... (attempt {})",
+                            reason,
+                            delay.as_secs(),
+                            register_failures
+                        );
+                        if sleep_or_shutdown(delay, &mut shutdown).await {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
                 info!("Registered to controller: {:?}", client_conf);
+                let version = client_conf
+                    .data
+                    .as_ref()
+                    .and_then(|data| json_int(data, "protocol_version"));
+                match version {
+                    Some(version) if version != PROTOCOL_VERSION => {
+                        register_failed(&mut register_failures, &config)?;
+                        let delay = backoff.next_delay();
+                        error!(
+                            "Controller speaks protocol version {}, agent speaks {}, retry in {} seconds... (attempt {})",
+                            version,
+                            PROTOCOL_VERSION,
+                            delay.as_secs(),
+                            register_failures
+                        );
+                        if sleep_or_shutdown(delay, &mut shutdown).await {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    Some(_) => {}
+                    None => warn!(
+                        "Controller did not advertise a protocol version, assume version {}",
+                        PROTOCOL_VERSION
+                    ),
+                }
                 let ws_url = if let Some(data) = client_conf.data {
                     if let Some(ws) = json_str(&data, "ws") {
                         if json_bool(&data, "redirct").is_some_and(|v| v) {
                             Some(ws)
                         } else {
-                            Some(format!(
-                                "{}://{}:{}/{}",
-                                if config.https { "wss" } else { "ws" },
-                                config.addr,
-                                config.port,
-                                ws
-                            ))
+                            Some(format!("{}/{}", controller.ws_base_url(), ws))
                         }
                     } else {
                         Some(format!(
-                            "{}://{}:{}/{}/ws",
-                            if config.https { "wss" } else { "ws" },
-                            config.addr,
-                            config.port,
-                            config.api_base_path
+                            "{}/{}/{}",
+                            controller.ws_base_url(),
+                            config.api_base_path,
+                            config.ws_path
                         ))
                     }
                 } else {
                     None
                 };
                 if ws_url.is_none() {
-                    error!("Failed to get websocket URL from controller, retry in 15 seconds...");
-                    tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
+                    register_failed(&mut register_failures, &config)?;
+                    let delay = backoff.next_delay();
+                    error!(
+                        "Failed to get websocket URL from controller, retry in {} seconds... (attempt {})",
+                        delay.as_secs(),
+                        register_failures
+                    );
+                    if sleep_or_shutdown(delay, &mut shutdown).await {
+                        return Ok(());
+                    }
                     continue;
                 }
                 let ws_url = ws_url.unwrap(); // Safe to unwrap here
                 info!("Connecting to controller websocket: {}", ws_url);
-                let (ws, _) = connect_async(ws_url).await?;
-                let (mut tx, mut rx) = ws.split();
+                let ws_request = net::build_ws_request(&ws_url, &config, &user_agent)?;
+                let connect = tokio::time::timeout(
+                    Duration::from_secs(config.connect_timeout_secs),
+                    net::connect_ws(ws_request, &config, ws_connector.clone()),
+                )
+                .await
+                .map_err(|_| anyhow::anyhow!("Timed out connecting to controller websocket"));
+                let ws = match connect.and_then(|result| result) {
+                    Ok(ws) => ws,
+                    Err(err) => {
+                        // Registering worked, but the controller is no use without a websocket
+                        register_failed(&mut register_failures, &config)?;
+                        match next_controller(&mut current, &mut failed, controllers, backoff) {
+                            None => warn!(
+                                "Failed to connect to controller websocket: {:#}. Try next one (attempt {})",
+                                err, register_failures
+                            ),
+                            Some(delay) => {
+                                error!(
+                                    "Failed to connect to controller websocket: {:#}. Retry in {} seconds... (attempt {})",
+                                    err,
+                                    delay.as_secs(),
+                                    register_failures
+                                );
+                                if sleep_or_shutdown(delay, &mut shutdown).await {
+                                    return Ok(());
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                };
+                let (mut ws_tx, mut rx) = ws.split();
+                backoff.reset();
+                failed = 0;
+                register_failures = 0;
+                let connected = state.record_connected();
+                let (tx, mut outbox) = mpsc::unbounded_channel::<Message>();
+                for entry in state.take_interrupted() {
+                    let interrupted = EventMessage {
+                        id: entry.id,
+                        event: "task_interrupted".to_string(),
+                        code: TaskResultCode::Success.into(),
+                        idempotency_key: None,
+                        data: Some(hashmap! {
+                            "type".to_string() => Value::String(entry.kind),
+                            "started_at".to_string() => json!(entry.time),
+                        }),
+                    };
+                    tx.send(Message::Text(json!(interrupted).to_string()))?;
+                }
+                let writer = tokio::spawn(async move {
+                    while let Some(msg) = outbox.recv().await {
+                        let closing = matches!(msg, Message::Close(_));
+                        if let Err(err) = ws_tx.send(msg).await {
+                            error!("Failed to send message to controller: {}", err);
+                            break;
+                        }
+                        if closing {
+                            break;
+                        }
+                    }
+                });
+                let log_forwarder = state
+                    .log_records
+                    .is_some()
+                    .then(|| AbortOnDrop(tokio::spawn(forward_logs(state.clone(), tx.clone()))));
                 trace!("Websocket connected to controller. Begin to handle message loop");
-                while let Some(event) = rx.next().await {
+                // A controller is considered dead when a Ping is not answered in time
+                let pong_timeout = Duration::from_secs(config.pong_timeout_secs);
+                let mut ping_interval =
+                    tokio::time::interval(Duration::from_secs(config.ping_interval_secs.max(1)));
+                ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                let ping_enabled = config.ping_interval_secs > 0;
+                let mut ping_sent: Option<Instant> = None;
+                // Catches half-open connections Pings alone may not, if controller never answers
+                let idle_timeout = config.idle_timeout();
+                let mut last_frame = Instant::now();
+                // Wait asked for by controller when closing, before connecting again
+                let mut reconnect_delay = None;
+                // Running tasks finishing after shutdown began, events are still read meanwhile
+                let mut drain = None;
+                loop {
+                    let pong_deadline = ping_sent.map(|sent| sent + pong_timeout);
+                    let idle_deadline = idle_timeout.map(|timeout| last_frame + timeout);
+                    let event = select! {
+                        // Prefer pending frames so a queued Pong is seen before the timeout fires
+                        biased;
+                        _ = shutdown.wait_for(|v| *v), if drain.is_none() => {
+                            info!(
+                                "Shutting down, wait up to {} seconds for running tasks",
+                                config.shutdown_timeout_secs
+                            );
+                            drain = Some(Box::pin(
+                                state.drain_tasks(Duration::from_secs(config.shutdown_timeout_secs)),
+                            ));
+                            continue;
+                        }
+                        _ = async { drain.as_mut().unwrap().await }, if drain.is_some() => {
+                            info!("Closing connection to controller");
+                            tx.send(Message::Close(Some(CloseFrame {
+                                code: CloseCode::Normal,
+                                reason: "agent shutting down".into(),
+                            })))?;
+                            _ = writer.await;
+                            return Ok(());
+                        }
+                        event = rx.next() => {
+                            last_frame = Instant::now();
+                            event
+                        }
+                        _ = ping_interval.tick(), if ping_enabled && ping_sent.is_none() => {
+                            tx.send(Message::Ping(Vec::new()))?;
+                            ping_sent = Some(Instant::now());
+                            debug!("Ping sent to controller");
+                            continue;
+                        }
+                        _ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                            warn!(
+                                "No Pong from controller in {} seconds, reconnect",
+                                pong_timeout.as_secs()
+                            );
+                            break;
+                        }
+                        _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                            warn!(
+                                "No frame from controller in {} seconds, reconnect",
+                                config.idle_timeout_secs
+                            );
+                            break;
+                        }
+                    };
+                    let Some(event) = event else {
+                        break;
+                    };
                     match event {
                         Ok(ws_msg) => {
                             debug!("Received message: {:?}", ws_msg);
                             match ws_msg {
                                 Message::Text(msg) => {
                                     trace!("Received text message from controller");
-                                    let event_msg: EventMessage = serde_json::from_str(&msg)?;
+                                    let event_msg: EventMessage = match serde_json::from_str(&msg) {
+                                        Ok(event_msg) => event_msg,
+                                        Err(err) => {
+                                            reject_invalid_message(&msg, err, &tx)?;
+                                            continue;
+                                        }
+                                    };
                                     log::info!("Received event: {:?}", event_msg);
-                                    _ = handle_message(Event::from(event_msg), &mut tx, &client)
-                                        .inspect_err(|err| {
-                                            error!("Failed to handle message: {}", err);
-                                        });
+                                    let key = event_msg.idempotency_key.clone();
+                                    dispatch(
+                                        state.handlers.event(event_msg),
+                                        key,
+                                        &tx,
+                                        &client,
+                                        &config,
+                                        &state,
+                                    )
+                                    .await;
                                 }
                                 Message::Binary(_) => {
                                     // Binary message from controller, do nothing
@@ -271,17 +2493,30 @@ async fn agent_main(config: config::Config) -> Result<()> {
                                     debug!("Received binary message from controller");
                                 }
                                 Message::Ping(msg) => {
-                                    tx.send(Message::Pong(msg)).await?;
+                                    tx.send(Message::Pong(msg))?;
                                     debug!("Received Ping from controller, Pong sent");
                                 }
                                 Message::Pong(_) => {
-                                    // Pong message from controller, do nothing
-                                    // In fact, agent will never send ping message as of now
+                                    ping_sent = None;
+                                    ping_interval.reset();
                                     debug!("Received Pong from controller");
                                 }
-                                Message::Close(_) => {
+                                Message::Close(frame) => {
                                     // Unexpected close message from controller. Connection closing should be initiated by agent or by specific event from controller
-                                    warn!("Websocket connection closed, retry");
+                                    if let Some(frame) = frame {
+                                        warn!(
+                                            "Websocket connection closed by controller: code = {}, reason = {:?}, retry",
+                                            frame.code, frame.reason
+                                        );
+                                        reconnect_delay = close_retry_after(&frame, &config)
+                                            .or_else(|| {
+                                                (frame.code == CloseCode::Again)
+                                                    .then(|| backoff.next_delay())
+                                            });
+                                    } else {
+                                        warn!("Websocket connection closed by controller, retry");
+                                    }
+                                    break;
                                 }
                                 Message::Frame(_) => {
                                     // As tungstenite noted, this should not happen, maybe a malformed controller response
@@ -289,51 +2524,235 @@ async fn agent_main(config: config::Config) -> Result<()> {
                                 }
                             }
                         }
+                        Err(WsError::Capacity(CapacityError::MessageTooLong {
+                            size,
+                            max_size,
+                        })) => {
+                            error!(
+                                "Rejected a message of {} bytes from controller, the limit is {} bytes, retry",
+                                size, max_size
+                            );
+                            break;
+                        }
                         Err(err) => {
-                            error!("Failed to receive message: {}", err);
+                            // The stream is unusable after an error, reading on would busy-loop
+                            error!("Failed to receive message: {}, retry", err);
+                            break;
                         }
                     }
                 }
+                writer.abort();
+                if let Some(drain) = drain {
+                    // Results can't be sent anymore, but work already begun may still finish
+                    drain.await;
+                    return Ok(());
+                }
+                if let Some(delay) = reconnect_delay {
+                    drop((connected, log_forwarder));
+                    info!("Reconnect in {} seconds", delay.as_secs());
+                    if sleep_or_shutdown(delay, &mut shutdown).await {
+                        return Ok(());
+                    }
+                }
             }
             Err(err) => {
+                // Timeouts count as failed connections, moving on to the next controller
+                register_failed(&mut register_failures, &config)?;
+                let err = register_error(&err, &config);
+                let Some(delay) = next_controller(&mut current, &mut failed, controllers, backoff)
+                else {
+                    warn!(
+                        "Failed to register to controller: {}. Try next one (attempt {})",
+                        err, register_failures
+                    );
+                    continue;
+                };
                 error!(
-                    "Failed to connect to controller: {}. Retry in 15 seconds...",
-                    err
+                    "Failed to register to controller: {}. Retry in {} seconds... (attempt {})",
+                    err,
+                    delay.as_secs(),
+                    register_failures
                 );
-                tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
+                if sleep_or_shutdown(delay, &mut shutdown).await {
+                    return Ok(());
+                }
             }
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    if let Err(err) = log4rs::init_file("log4rs.yml", Default::default()) {
-        eprintln!("Failed to initialize log4rs: {}", err);
-        if let Ok(config) = Config::builder()
-            .appender(
-                Appender::builder().build("stdout", Box::new(ConsoleAppender::builder().build())),
-            )
-            .build(Root::builder().appender("stdout").build(LevelFilter::Debug))
-        {
-            if log4rs::init_config(config).is_err() {
-                eprintln!("Failed to initialize log4rs with default config");
-                return;
-            }
-        } else {
-            eprintln!("Failed to construct default log4rs config");
-            return;
-        }
-    }
-
+fn main() {
     let args = config::Args::parse();
+    if !logging::init(&args) {
+        return;
+    }
     let config = config::Config::from(args);
+    if let Err(err) = config.validate() {
+        error!("Invalid configuration: {}", err);
+        std::process::exit(1);
+    }
     info!("MetalX Agent - Launching with config: {:?}", config);
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.worker_threads {
+        runtime.worker_threads(threads);
+    }
+    let runtime = match runtime.build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("Failed to start async runtime: {}", err);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run(config));
+}
+
+/// Run the agent until shutdown is requested.
+async fn run(config: config::Config) {
+    // Event types added by forks are registered here with `register_handler`
+    let handlers = builtin_handlers();
+    let state = match AgentState::new(&config, handlers) {
+        Ok(state) => Arc::new(state),
+        Err(err) => {
+            error!("Invalid configuration: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    // Read once, connections to several controllers must not race creating the machine id
+    let machine_uuid = match utils::get_machine_uuid(config.client_id(), &config.machine_id_path) {
+        Ok((machine_uuid, source)) => {
+            info!("Use machine UUID from {}: {}", source, machine_uuid);
+            machine_uuid
+        }
+        Err(err) => {
+            error!("Failed to get machine UUID: {:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let system_info = SystemInfo::collect();
+    info!("Running on {:?}", system_info);
+    let privileged = match utils::system_info::effective_uid() {
+        Some(0) => Some(true),
+        Some(uid) => {
+            warn!(
+                "Agent is not running as root but as user {}, tasks writing to system paths, \
+                 reading DMI or changing file modes may fail with permission errors",
+                uid
+            );
+            Some(false)
+        }
+        None => {
+            info!("Privileges of the agent are unknown on this platform");
+            None
+        }
+    };
+    let hostname = utils::system_info::hostname();
+    info!("Hostname: {}", hostname.as_deref().unwrap_or("unknown"));
+    let identity = AgentIdentity {
+        machine_uuid,
+        system_info,
+        privileged,
+        hostname,
+    };
+    let endpoints = [
+        (Endpoint::Health, config.health_port, &config.health_addr),
+        (Endpoint::Metrics, config.metrics_port, &config.metrics_addr),
+    ];
+    for (endpoint, port, addr) in endpoints {
+        let Some(port) = port else {
+            continue;
+        };
+        let listener = match TcpListener::bind((addr.as_str(), port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!(
+                    "Failed to listen for {:?} requests on {}:{}: {}",
+                    endpoint, addr, port, err
+                );
+                std::process::exit(1);
+            }
+        };
+        info!("Serving {:?} requests on {}:{}", endpoint, addr, port);
+        tokio::spawn(health::serve(
+            listener,
+            endpoint,
+            config.clone(),
+            state.clone(),
+        ));
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let signal_state = state.clone();
+    tokio::spawn(async move {
+        utils::wait_for_shutdown_signal().await;
+        info!("Received shutdown signal");
+        signal_state.begin_shutdown();
+        _ = shutdown_tx.send(true);
+    });
+    let controllers = config.controllers();
+    let connections = if config.connect_all_controllers {
+        controllers
+            .into_iter()
+            .map(|controller| vec![controller])
+            .collect()
+    } else {
+        vec![controllers]
+    };
+    let connections = connections.iter().map(|controllers| {
+        run_connection(
+            &config,
+            controllers,
+            &identity,
+            state.clone(),
+            shutdown_rx.clone(),
+        )
+    });
+    let results = futures_util::future::join_all(connections).await;
+    if results.iter().all(Result::is_err) {
+        error!("Gave up on all controllers, exit");
+        std::process::exit(1);
+    }
+    info!("MetalX Agent - Shut down");
+}
+
+/// Keep a connection to one of `controllers` up until shutdown, restarting it when it fails.
+///
+/// Fails once `max_register_attempts` registrations failed in a row.
+async fn run_connection(
+    config: &config::Config,
+    controllers: &[ControllerEndpoint],
+    identity: &AgentIdentity,
+    state: Arc<AgentState>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut backoff = Backoff::new(
+        Duration::from_secs(config.backoff_base_secs),
+        Duration::from_secs(config.backoff_max_secs),
+    );
     loop {
-        if let Err(err) = agent_main(config.clone()).await {
-            error!("Agent failed: {}", err);
-            info!("Restart in 60 seconds...");
-            tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
+        match agent_main(
+            config.clone(),
+            controllers,
+            identity,
+            &mut backoff,
+            state.clone(),
+            shutdown.clone(),
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is::<RegisterAttemptsExhausted>() => {
+                // Connections to other controllers carry on, the agent exits once all gave up
+                error!("{}, give up", err);
+                return Err(err);
+            }
+            Err(err) => {
+                error!("Agent failed: {}", err);
+                let delay = backoff.next_delay();
+                info!("Restart in {} seconds...", delay.as_secs());
+                if sleep_or_shutdown(delay, &mut shutdown).await {
+                    return Ok(());
+                }
+            }
         }
     }
 }
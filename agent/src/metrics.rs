@@ -0,0 +1,131 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::state::AgentState;
+
+/// Counts of the tasks of one event type since the agent started.
+#[derive(Debug, Default, Clone, Copy)]
+struct TaskCounts {
+    started: u64,
+    completed: u64,
+    /// Completed tasks whose result code tells a failure
+    failed: u64,
+}
+
+/// Counters of the agent process, exported in the Prometheus text format.
+///
+/// They're global so transfers can count bytes without being handed the agent state.
+struct Metrics {
+    tasks: Mutex<BTreeMap<&'static str, TaskCounts>>,
+    downloaded_bytes: AtomicU64,
+    uploaded_bytes: AtomicU64,
+}
+
+static METRICS: Metrics = Metrics {
+    tasks: Mutex::new(BTreeMap::new()),
+    downloaded_bytes: AtomicU64::new(0),
+    uploaded_bytes: AtomicU64::new(0),
+};
+
+/// Count a task of type `kind` that began its work.
+pub(crate) fn task_started(kind: &'static str) {
+    METRICS
+        .tasks
+        .lock()
+        .unwrap()
+        .entry(kind)
+        .or_default()
+        .started += 1;
+}
+
+/// Count a task of type `kind` that completed, `failed` telling whether it went wrong.
+pub(crate) fn task_completed(kind: &'static str, failed: bool) {
+    let mut tasks = METRICS.tasks.lock().unwrap();
+    let counts = tasks.entry(kind).or_default();
+    counts.completed += 1;
+    if failed {
+        counts.failed += 1;
+    }
+}
+
+/// Count bytes received by a download.
+pub(crate) fn downloaded(bytes: u64) {
+    METRICS.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count bytes sent by an upload.
+pub(crate) fn uploaded(bytes: u64) {
+    METRICS.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Render the metrics of the agent in the Prometheus text exposition format.
+pub(crate) fn render(state: &AgentState) -> String {
+    let mut out = String::new();
+    let tasks = METRICS.tasks.lock().unwrap().clone();
+    let per_type = [
+        ("started", "Tasks that began their work"),
+        ("completed", "Tasks that completed"),
+        ("failed", "Tasks that completed with a failure"),
+    ];
+    for (name, help) in per_type {
+        // Writing to a String can't fail
+        _ = writeln!(
+            out,
+            "# HELP metalx_tasks_{}_total {}, by event type.",
+            name, help
+        );
+        _ = writeln!(out, "# TYPE metalx_tasks_{}_total counter", name);
+        for (kind, counts) in &tasks {
+            let value = match name {
+                "started" => counts.started,
+                "completed" => counts.completed,
+                _ => counts.failed,
+            };
+            _ = writeln!(
+                out,
+                "metalx_tasks_{}_total{{type=\"{}\"}} {}",
+                name, kind, value
+            );
+        }
+    }
+    let in_flight = state.tasks.lock().unwrap().len();
+    let reconnects = state.connection.lock().unwrap().reconnects;
+    let values = [
+        (
+            "metalx_tasks_in_flight",
+            "gauge",
+            "Tasks running or waiting for a free slot.",
+            in_flight as u64,
+        ),
+        (
+            "metalx_downloaded_bytes_total",
+            "counter",
+            "Bytes received by downloads.",
+            METRICS.downloaded_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "metalx_uploaded_bytes_total",
+            "counter",
+            "Bytes sent by uploads.",
+            METRICS.uploaded_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "metalx_reconnects_total",
+            "counter",
+            "Websocket connections made after the first one.",
+            reconnects,
+        ),
+    ];
+    for (name, kind, help, value) in values {
+        _ = writeln!(out, "# HELP {} {}", name, help);
+        _ = writeln!(out, "# TYPE {} {}", name, kind);
+        _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
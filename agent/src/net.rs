@@ -0,0 +1,275 @@
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{error, info};
+use percent_encoding::percent_decode_str;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    client_async_tls_with_config, connect_async_tls_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        error::{ProtocolError, SubProtocolError},
+        handshake::client::{Request, Response},
+        http::{
+            header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, USER_AGENT},
+            HeaderValue,
+        },
+        protocol::WebSocketConfig,
+    },
+    Connector, MaybeTlsStream, WebSocketStream,
+};
+
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// User-agent identifying this agent, like `MetalX-Agent/0.1.0 (linux; x86_64)`.
+pub(crate) fn user_agent(config: &Config, machine_uuid: &Uuid) -> String {
+    let product = match &config.user_agent {
+        Some(product) => product.clone(),
+        None => format!("MetalX-Agent/{}", env!("CARGO_PKG_VERSION")),
+    };
+    let mut comment = format!("{}; {}", std::env::consts::OS, std::env::consts::ARCH);
+    if config.user_agent_machine_id {
+        comment += &format!("; {}", machine_uuid);
+    }
+    format!("{} ({})", product, comment)
+}
+
+/// Build the HTTP client used for registration and file transfers.
+pub(crate) fn build_http_client(config: &Config, user_agent: &str) -> Result<reqwest::Client> {
+    // Uploads may take long before the response, so only downloads enforce a read timeout
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent)
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs));
+    // The port of URLs is used, the one given here is ignored
+    for host in config.hosts.keys() {
+        if let Some(ip) = config.host_addr(host) {
+            builder = builder.resolve(host, SocketAddr::new(ip, 0));
+        }
+    }
+    if let Some((cert, key)) = load_client_identity(config)? {
+        builder = builder.identity(reqwest::Identity::from_pkcs8_pem(&cert, &key)?);
+    }
+    if let Some((path, ca)) = load_ca_cert(config)? {
+        let ca = reqwest::Certificate::from_pem(&ca).inspect_err(|err| {
+            error!("Failed to parse CA certificate {}: {:?}", path, err);
+        })?;
+        builder = builder.add_root_certificate(ca);
+    }
+    // Explicit proxies turn off the environment lookup of reqwest, so NO_PROXY is read here
+    if let Some(proxy) = config.proxy(false) {
+        let proxy = reqwest::Proxy::http(proxy).context("Invalid HTTP proxy")?;
+        builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+    }
+    if let Some(proxy) = config.proxy(true) {
+        let proxy = reqwest::Proxy::https(proxy).context("Invalid HTTPS proxy")?;
+        builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+    }
+    Ok(builder.build()?)
+}
+
+/// Build the TLS connector for the websocket connection, `None` to use the default one.
+pub(crate) fn build_ws_connector(config: &Config) -> Result<Option<Connector>> {
+    let identity = load_client_identity(config)?;
+    let ca = load_ca_cert(config)?;
+    if identity.is_none() && ca.is_none() {
+        return Ok(None);
+    }
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some((cert, key)) = identity {
+        builder.identity(native_tls::Identity::from_pkcs8(&cert, &key)?);
+    }
+    if let Some((path, ca)) = ca {
+        let ca = native_tls::Certificate::from_pem(&ca).inspect_err(|err| {
+            error!("Failed to parse CA certificate {}: {:?}", path, err);
+        })?;
+        builder.add_root_certificate(ca);
+    }
+    Ok(Some(Connector::NativeTls(builder.build()?)))
+}
+
+/// Build the websocket upgrade request, authenticated with the configured token.
+pub(crate) fn build_ws_request(url: &str, config: &Config, user_agent: &str) -> Result<Request> {
+    let mut request = url.into_client_request()?;
+    request
+        .headers_mut()
+        .insert(USER_AGENT, HeaderValue::from_str(user_agent)?);
+    if let Some(token) = &config.auth_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token.expose()))?;
+        value.set_sensitive(true);
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    if let Some(protocol) = &config.ws_subprotocol {
+        request
+            .headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(protocol)?);
+    }
+    Ok(request)
+}
+
+/// Open the websocket connection to controller, tunnelled through the proxy if one is set.
+///
+/// Fails if controller doesn't select the `ws_subprotocol` requested.
+pub(crate) async fn connect_ws(
+    request: Request,
+    config: &Config,
+    connector: Option<Connector>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    // Sizes are checked before payloads are buffered, so a huge frame can't exhaust memory
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.ws_max_message_bytes),
+        max_frame_size: Some(config.ws_max_frame_bytes),
+        ..Default::default()
+    };
+    let https = request.uri().scheme_str() == Some("wss");
+    let host = request
+        .uri()
+        .host()
+        .context("Websocket URL without host")?
+        .to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if https { 443 } else { 80 });
+    let stream = match config.proxy(https) {
+        Some(proxy) => connect_tunnel(&proxy, &host, port, config).await?,
+        None if config.host_addr(&host).is_some() => connect_tcp(&host, port, config).await?,
+        None => {
+            let result =
+                connect_async_tls_with_config(request, Some(ws_config), false, connector).await;
+            return check_subprotocol(result, config);
+        }
+    };
+    let result = client_async_tls_with_config(request, stream, Some(ws_config), connector).await;
+    check_subprotocol(result, config)
+}
+
+/// Explain a websocket upgrade failing on subprotocol negotiation.
+fn check_subprotocol<T>(
+    result: Result<(T, Response), tungstenite::Error>,
+    config: &Config,
+) -> Result<T> {
+    let requested = config.ws_subprotocol.as_deref().unwrap_or_default();
+    match result {
+        Ok((ws, response)) => {
+            let selected = response
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .map(|value| value.to_str().unwrap_or_default());
+            // Tungstenite checks this already, make sure it never lets a mismatch through
+            if selected != config.ws_subprotocol.as_deref() {
+                bail!(
+                    "Controller selected websocket subprotocol {:?} instead of {:?}",
+                    selected.unwrap_or_default(),
+                    requested
+                );
+            }
+            Ok(ws)
+        }
+        Err(tungstenite::Error::Protocol(ProtocolError::SecWebSocketSubProtocolError(err))) => {
+            match err {
+                SubProtocolError::NoSubProtocol => {
+                    bail!("Controller didn't select websocket subprotocol {}", requested)
+                }
+                SubProtocolError::InvalidSubProtocol => {
+                    bail!(
+                        "Controller selected another websocket subprotocol than {}",
+                        requested
+                    )
+                }
+                SubProtocolError::ServerSentSubProtocolNoneRequested => bail!(
+                    "Controller selected a websocket subprotocol though none was requested, set ws_subprotocol"
+                ),
+            }
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Open a TCP connection to `host:port`, using the address `hosts` maps `host` to if any.
+async fn connect_tcp(host: &str, port: u16, config: &Config) -> Result<TcpStream> {
+    let stream = match config.host_addr(host) {
+        Some(ip) => TcpStream::connect((ip, port)).await?,
+        None => TcpStream::connect((host, port)).await?,
+    };
+    Ok(stream)
+}
+
+/// Open a TCP connection to `host:port` through an HTTP proxy with a CONNECT request.
+///
+/// The proxy resolves `host` itself, only the address of the proxy is looked up in `hosts`.
+async fn connect_tunnel(proxy: &str, host: &str, port: u16, config: &Config) -> Result<TcpStream> {
+    let proxy = if proxy.contains("://") {
+        reqwest::Url::parse(proxy)
+    } else {
+        reqwest::Url::parse(&format!("http://{}", proxy))
+    }
+    .context("Invalid proxy")?;
+    if proxy.scheme() != "http" {
+        bail!("Websocket connections only support http:// proxies");
+    }
+    let proxy_host = proxy.host_str().context("Proxy without host")?;
+    let proxy_port = proxy.port().unwrap_or(80);
+    info!("Connect through proxy {}:{}", proxy_host, proxy_port);
+    let mut stream = connect_tcp(proxy_host, proxy_port, config).await?;
+    let target = format!("{}:{}", host, port);
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            percent_decode_str(proxy.username()).decode_utf8_lossy(),
+            percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8_lossy()
+        );
+        request += &format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            STANDARD.encode(credentials)
+        );
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+    // Read byte by byte so nothing after the response head is taken from the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= 8192 {
+            bail!("Proxy response head too long");
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        bail!("Proxy refused tunnel to {}: {}", target, status_line);
+    }
+    Ok(stream)
+}
+
+/// Read the PEM client certificate and PKCS#8 key used for mTLS, if configured.
+fn load_client_identity(config: &Config) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match (&config.client_cert_path, &config.client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("Use TLS client certificate {}", cert_path);
+            let cert = std::fs::read(cert_path)
+                .with_context(|| format!("Failed to read client certificate {}", cert_path))?;
+            let key = std::fs::read(key_path.expose()).context("Failed to read client key")?;
+            Ok(Some((cert, key)))
+        }
+        (None, None) => Ok(None),
+        _ => anyhow::bail!("client_cert_path and client_key_path must be set together"),
+    }
+}
+
+/// Read the PEM CA certificate trusted in addition to the system roots, if configured.
+fn load_ca_cert(config: &Config) -> Result<Option<(&str, Vec<u8>)>> {
+    let Some(path) = &config.ca_cert_path else {
+        return Ok(None);
+    };
+    info!("Trust CA certificate {}", path);
+    let ca =
+        std::fs::read(path).with_context(|| format!("Failed to read CA certificate {}", path))?;
+    Ok(Some((path, ca)))
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+use log::{info, warn};
+use regex::RegexSet;
+use serde_json::{json, Value};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    config,
+    handlers::HandlerRegistry,
+    journal::{Journal, JournalEntry, TaskStatus},
+    logging::{self, ForwardedRecord},
+    utils, EventMessage,
+};
+
+/// A task spawned by the message loop that has not finished yet.
+pub(crate) struct RunningTask {
+    pub handle: JoinHandle<()>,
+    /// Event type of the task, e.g. `download`
+    pub kind: &'static str,
+    /// File to remove when the task is cancelled
+    pub partial_file: Option<String>,
+}
+
+/// History of the websocket connection to controller.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    /// Time the websocket was last connected
+    pub last_connected_at: Option<SystemTime>,
+    /// Connections made after the first one
+    pub reconnects: u64,
+    /// Websocket connections currently open
+    pub open: usize,
+}
+
+/// Open websocket connection, counted in `ConnectionStats::open` until dropped.
+pub(crate) struct OpenConnection<'a> {
+    state: &'a AgentState,
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.state.connection.lock().unwrap().open -= 1;
+    }
+}
+
+/// Ids of the most recently accepted tasks, oldest first out.
+struct SeenTasks {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+/// Replies of recently completed tasks by idempotency key, least recently used first out.
+struct CompletedKeys {
+    replies: HashMap<String, (Instant, EventMessage)>,
+    order: VecDeque<String>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl CompletedKeys {
+    /// Forget replies older than `ttl`.
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.replies
+            .retain(|_, (completed_at, _)| completed_at.elapsed() < ttl);
+        let replies = &self.replies;
+        self.order.retain(|key| replies.contains_key(key));
+    }
+}
+
+/// Runtime state of the agent, shared across reconnects.
+pub(crate) struct AgentState {
+    /// Time the agent process was started
+    pub started_at: Instant,
+    /// Running tasks by task id, tasks remove themselves once finished
+    pub tasks: Mutex<HashMap<u64, RunningTask>>,
+    /// Permits for tasks allowed to do work at the same time
+    pub task_slots: Semaphore,
+    /// Compiled `command_allowlist`, `None` if every command is allowed
+    command_allowlist: Option<RegexSet>,
+    pub connection: Mutex<ConnectionStats>,
+    /// Tasks waiting for controller to answer one of their requests, by task id
+    replies: Mutex<HashMap<u64, oneshot::Sender<EventMessage>>>,
+    /// Journal of accepted tasks, `None` if `journal_path` is not set
+    journal: Option<Journal>,
+    /// Tasks an earlier run left unfinished, not yet reported to controller
+    interrupted: Mutex<Vec<JournalEntry>>,
+    /// Accepted tasks, `None` unless `connect_all_controllers` is set
+    seen_tasks: Option<Mutex<SeenTasks>>,
+    /// Log records to forward, drained by one connection at a time. `None` unless
+    /// `forward_log_level` is set
+    pub log_records: Option<tokio::sync::Mutex<mpsc::Receiver<ForwardedRecord>>>,
+    /// Set once graceful shutdown began, new tasks are rejected from then on
+    shutting_down: AtomicBool,
+    /// Task events handled by the agent
+    pub handlers: HandlerRegistry,
+    /// Idempotency keys of running tasks by task id
+    running_keys: Mutex<HashMap<u64, String>>,
+    completed_keys: Mutex<CompletedKeys>,
+}
+
+/// Characters letting a shell run more than the first command of a line.
+const SHELL_CONTROL_CHARS: &[char] = &[';', '|', '&', '$', '`', '<', '>', '(', ')', '\n'];
+
+impl AgentState {
+    pub(crate) fn new(config: &config::Config, handlers: HandlerRegistry) -> Result<Self> {
+        let command_allowlist = if config.command_allowlist.is_empty() {
+            None
+        } else {
+            let patterns = config
+                .command_allowlist
+                .iter()
+                .map(|pattern| format!("^(?:{})$", pattern));
+            Some(RegexSet::new(patterns).context("Invalid command allowlist")?)
+        };
+        let (journal, interrupted) = match &config.journal_path {
+            Some(path) => {
+                let (journal, interrupted) = Journal::open(path)?;
+                (Some(journal), interrupted)
+            }
+            None => (None, Vec::new()),
+        };
+        Ok(AgentState {
+            started_at: Instant::now(),
+            tasks: Mutex::new(HashMap::new()),
+            task_slots: Semaphore::new(config.max_concurrent_tasks.max(1)),
+            command_allowlist,
+            connection: Mutex::new(ConnectionStats::default()),
+            replies: Mutex::new(HashMap::new()),
+            journal,
+            interrupted: Mutex::new(interrupted),
+            seen_tasks: config.connect_all_controllers.then(|| {
+                Mutex::new(SeenTasks {
+                    ids: HashSet::new(),
+                    order: VecDeque::new(),
+                    capacity: config.task_dedup_window,
+                })
+            }),
+            log_records: config
+                .forward_log_level
+                .map(|level| tokio::sync::Mutex::new(logging::forward_to_controller(level))),
+            shutting_down: AtomicBool::new(false),
+            handlers,
+            running_keys: Mutex::new(HashMap::new()),
+            completed_keys: Mutex::new(CompletedKeys {
+                replies: HashMap::new(),
+                order: VecDeque::new(),
+                capacity: config.idempotency_cache_size,
+                ttl: Duration::from_secs(config.idempotency_ttl_secs),
+            }),
+        })
+    }
+
+    /// Record a successful websocket connection and log how it relates to the previous one.
+    ///
+    /// The connection counts as open until the returned guard is dropped.
+    #[must_use]
+    pub(crate) fn record_connected(&self) -> OpenConnection<'_> {
+        let mut connection = self.connection.lock().unwrap();
+        let now = SystemTime::now();
+        if let Some(previous) = connection.last_connected_at {
+            connection.reconnects += 1;
+            info!(
+                "Reconnected to controller: {} reconnects since start, previous connection made {} seconds ago",
+                connection.reconnects,
+                now.duration_since(previous).unwrap_or_default().as_secs()
+            );
+        }
+        connection.last_connected_at = Some(now);
+        connection.open += 1;
+        OpenConnection { state: self }
+    }
+
+    /// Record a change of status of the task `id` in the journal, if enabled.
+    pub(crate) fn journal(&self, id: u64, kind: &str, status: TaskStatus) {
+        if let Some(journal) = &self.journal {
+            journal.record(id, kind, status);
+        }
+    }
+
+    /// Take the tasks an earlier run left unfinished, marking them as reported.
+    pub(crate) fn take_interrupted(&self) -> Vec<JournalEntry> {
+        let interrupted = std::mem::take(&mut *self.interrupted.lock().unwrap());
+        for entry in &interrupted {
+            self.journal(entry.id, &entry.kind, TaskStatus::Interrupted);
+        }
+        interrupted
+    }
+
+    /// Whether the task `id` was accepted before, from this or another controller.
+    pub(crate) fn already_accepted(&self, id: u64) -> bool {
+        self.seen_tasks
+            .as_ref()
+            .is_some_and(|seen| seen.lock().unwrap().ids.contains(&id))
+    }
+
+    /// Remember the task `id` as accepted, forgetting the oldest one beyond the window.
+    pub(crate) fn remember_accepted(&self, id: u64) {
+        let Some(seen) = &self.seen_tasks else {
+            return;
+        };
+        let mut seen = seen.lock().unwrap();
+        if seen.ids.insert(id) {
+            seen.order.push_back(id);
+        }
+        while seen.order.len() > seen.capacity {
+            if let Some(oldest) = seen.order.pop_front() {
+                seen.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// Reply of the task with idempotency key `key` if it completed recently.
+    pub(crate) fn completed_reply(&self, key: &str) -> Option<EventMessage> {
+        let mut completed = self.completed_keys.lock().unwrap();
+        completed.expire();
+        let reply = completed.replies.get(key)?.1.clone();
+        completed.order.retain(|known| known != key);
+        completed.order.push_back(key.to_string());
+        Some(reply)
+    }
+
+    /// Whether a task with idempotency key `key` is running.
+    pub(crate) fn key_running(&self, key: &str) -> bool {
+        self.running_keys
+            .lock()
+            .unwrap()
+            .values()
+            .any(|running| running == key)
+    }
+
+    /// Remember that the task `id` runs with idempotency key `key`.
+    pub(crate) fn start_keyed(&self, id: u64, key: String) {
+        self.running_keys.lock().unwrap().insert(id, key);
+    }
+
+    /// Keep the reply of a task for redeliveries of it, if it has an idempotency key.
+    ///
+    /// The key is set in the reply, so controller can match it to the task delivered.
+    pub(crate) fn complete_keyed(&self, reply: &mut EventMessage) {
+        let Some(key) = self.running_keys.lock().unwrap().remove(&reply.id) else {
+            return;
+        };
+        reply.idempotency_key = Some(key.clone());
+        let mut completed = self.completed_keys.lock().unwrap();
+        if completed.capacity == 0 {
+            return;
+        }
+        completed.order.retain(|known| *known != key);
+        completed.order.push_back(key.clone());
+        completed
+            .replies
+            .insert(key, (Instant::now(), reply.clone()));
+        while completed.order.len() > completed.capacity {
+            if let Some(oldest) = completed.order.pop_front() {
+                completed.replies.remove(&oldest);
+            }
+        }
+    }
+
+    /// Forget the idempotency key of the task `id`, which ended without a reply to keep.
+    pub(crate) fn forget_keyed(&self, id: u64) {
+        self.running_keys.lock().unwrap().remove(&id);
+    }
+
+    /// Wait for the next reply of controller to the task `id`.
+    ///
+    /// Only one reply per task is awaited, a later call replaces the earlier one.
+    pub(crate) fn await_reply(&self, id: u64) -> oneshot::Receiver<EventMessage> {
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    /// Stop waiting for a reply to the task `id`.
+    pub(crate) fn forget_reply(&self, id: u64) {
+        self.replies.lock().unwrap().remove(&id);
+    }
+
+    /// Hand a reply over to the task waiting for it, giving it back if no task is waiting.
+    pub(crate) fn deliver_reply(&self, reply: EventMessage) -> Result<(), EventMessage> {
+        match self.replies.lock().unwrap().remove(&reply.id) {
+            Some(waiting) => waiting.send(reply),
+            None => Err(reply),
+        }
+    }
+
+    /// Check a shell command against `command_allowlist`.
+    pub(crate) fn command_allowed(&self, cmd: &str) -> bool {
+        let Some(allowlist) = &self.command_allowlist else {
+            return true;
+        };
+        if cmd.contains(SHELL_CONTROL_CHARS) {
+            return false;
+        }
+        cmd.split_whitespace()
+            .next()
+            .is_some_and(|program| allowlist.is_match(program))
+    }
+
+    /// Mark graceful shutdown as begun.
+    pub(crate) fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether graceful shutdown began.
+    pub(crate) fn shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Wait for running tasks to finish, aborting whatever is left after `timeout`.
+    pub(crate) async fn drain_tasks(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while !self.tasks.lock().unwrap().is_empty() {
+            if Instant::now() >= deadline {
+                let mut tasks = self.tasks.lock().unwrap();
+                warn!("Aborting {} tasks still running", tasks.len());
+                for (_, task) in tasks.drain() {
+                    task.handle.abort();
+                }
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Collect a health report of the agent, as answered to `status` events.
+    pub(crate) fn status(&self, config: &config::Config) -> HashMap<String, Value> {
+        let version = config::Args::command()
+            .get_version()
+            .unwrap_or_default()
+            .to_string();
+        let disk_free = utils::available_space(&config.status_disk_path)
+            .inspect_err(|err| {
+                log::warn!(
+                    "Failed to get free disk space of {}: {}",
+                    config.status_disk_path,
+                    err
+                )
+            })
+            .ok();
+        let mut status = HashMap::new();
+        status.insert("version".to_string(), json!(version));
+        status.insert(
+            "uptime_secs".to_string(),
+            json!(self.started_at.elapsed().as_secs()),
+        );
+        status.insert("load_average".to_string(), json!(utils::load_average()));
+        status.insert("disk_path".to_string(), json!(config.status_disk_path));
+        status.insert("disk_free_bytes".to_string(), json!(disk_free));
+        let connection = self.connection.lock().unwrap();
+        let last_connected_at = connection
+            .last_connected_at
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs());
+        status.insert("last_connected_at".to_string(), json!(last_connected_at));
+        status.insert("reconnects".to_string(), json!(connection.reconnects));
+        status.insert("shutting_down".to_string(), json!(self.shutting_down()));
+        status
+    }
+}
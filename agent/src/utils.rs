@@ -1,106 +1,1608 @@
 use std::{
+    collections::HashMap,
     fs::File,
+    future::Future,
     io::{Read, Write},
+    path::Path,
+    pin::Pin,
     process::Stdio,
+    sync::{Arc, Mutex},
+    task::{ready, Poll},
 };
 
-use anyhow::{anyhow, Result};
-use log::{error, info};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use futures_util::StreamExt;
+use log::{debug, error, info, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, RANGE},
+    multipart::{Form, Part},
+    Body, StatusCode,
+};
+use sha2::{Digest, Sha256, Sha512};
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    process::Command,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadBuf},
+    process::{Child, Command},
     select,
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
-/// Get the machine UUID from the DMI table.
-pub(crate) fn get_machine_uuid() -> Result<Uuid> {
-    let mut fd = File::open("/sys/firmware/dmi/entries/1-0/raw")?;
+use crate::metrics;
+
+pub(crate) mod integrity;
+pub(crate) mod mirror;
+pub(crate) mod packages;
+pub(crate) mod system_info;
+
+/// Raw SMBIOS system information entry, holding the UUID as bytes.
+const DMI_ENTRY_PATH: &str = "/sys/firmware/dmi/entries/1-0/raw";
+/// The UUID of the SMBIOS system information as text, exposed by kernels without the raw entry.
+const PRODUCT_UUID_PATH: &str = "/sys/class/dmi/id/product_uuid";
+/// Machine id of systemd and D-Bus, stable across reboots but regenerated on reinstall.
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Where the machine UUID was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UuidSource {
+    /// `client_id` of the configuration
+    Config,
+    DmiEntry,
+    ProductUuid,
+    MachineId,
+    /// File at the fallback path, generated on first use
+    Fallback,
+}
+
+impl std::fmt::Display for UuidSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UuidSource::Config => write!(f, "configuration"),
+            UuidSource::DmiEntry => write!(f, "{}", DMI_ENTRY_PATH),
+            UuidSource::ProductUuid => write!(f, "{}", PRODUCT_UUID_PATH),
+            UuidSource::MachineId => write!(f, "{}", MACHINE_ID_PATH),
+            UuidSource::Fallback => write!(f, "fallback file"),
+        }
+    }
+}
+
+/// Get the machine UUID and where it was read from, `client_id` when it's set.
+///
+/// Tries the raw DMI entry, the DMI product UUID as text and `/etc/machine-id` in that order.
+/// Falls back to a persistent UUID stored at `fallback_path`, which is generated on first use
+/// when none of them can be read (containers, VMs without SMBIOS, non-Linux hosts).
+pub(crate) fn get_machine_uuid(
+    client_id: Option<Uuid>,
+    fallback_path: &str,
+) -> Result<(Uuid, UuidSource)> {
+    if let Some(uuid) = client_id {
+        return Ok((uuid, UuidSource::Config));
+    }
+    for source in [
+        UuidSource::DmiEntry,
+        UuidSource::ProductUuid,
+        UuidSource::MachineId,
+    ] {
+        let uuid = match source {
+            UuidSource::DmiEntry => get_dmi_uuid(),
+            UuidSource::ProductUuid => read_uuid_file(PRODUCT_UUID_PATH),
+            _ => read_uuid_file(MACHINE_ID_PATH),
+        };
+        match uuid {
+            Ok(uuid) => return Ok((uuid, source)),
+            Err(err) => debug!("Failed to read machine UUID from {}: {}", source, err),
+        }
+    }
+    warn!(
+        "Failed to read machine UUID from DMI table or {}, fallback to {}",
+        MACHINE_ID_PATH, fallback_path
+    );
+    Ok((get_persistent_uuid(fallback_path)?, UuidSource::Fallback))
+}
+
+fn get_dmi_uuid() -> Result<Uuid> {
+    let mut fd = File::open(DMI_ENTRY_PATH)?;
     let mut buf: [u8; 24] = [0u8; 24];
     fd.read_exact(&mut buf)?;
     let buf2: [u8; 16] = buf[8..24].try_into()?;
     Ok(Uuid::from_bytes(buf2))
 }
 
+/// Read a UUID written as text at `path`, hyphenated or as 32 hex digits like `/etc/machine-id`.
+fn read_uuid_file(path: &str) -> Result<Uuid> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(Uuid::parse_str(content.trim())?)
+}
+
+/// Read the UUID stored at `path`, or generate and store a new one if it does not exist.
+fn get_persistent_uuid(path: &str) -> Result<Uuid> {
+    if let Ok(content) = std::fs::read_to_string(path) {
+        let uuid = Uuid::parse_str(content.trim())?;
+        info!("Use persistent machine UUID from {}: {}", path, uuid);
+        return Ok(uuid);
+    }
+    let uuid = Uuid::new_v4();
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, uuid.to_string())?;
+    warn!(
+        "Generated new machine UUID {} and stored it to {}. Identity is only stable as long as this file is kept",
+        uuid, path
+    );
+    Ok(uuid)
+}
+
+/// Get the 1, 5 and 15 minute load averages, `None` if not supported on this platform.
+pub(crate) fn load_average() -> Option<[f64; 3]> {
+    let content = std::fs::read_to_string("/proc/loadavg").ok()?;
+    let mut fields = content.split_whitespace().map(|v| v.parse::<f64>().ok());
+    Some([fields.next()??, fields.next()??, fields.next()??])
+}
+
+/// Get the bytes available to unprivileged users on the filesystem containing `path`.
+#[cfg(unix)]
+pub(crate) fn available_space(path: &str) -> Result<u64> {
+    let c_path = std::ffi::CString::new(path)?;
+    // SAFETY: `statvfs` only writes into the zero-initialized struct we own
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Get the bytes available to unprivileged users on the filesystem containing `path`.
+#[cfg(not(unix))]
+pub(crate) fn available_space(_path: &str) -> Result<u64> {
+    anyhow::bail!("Free disk space is not supported on this platform")
+}
+
+/// Make `new_path` executable and rename it over the executable at `exe`.
+///
+/// The rename is atomic as long as both are on the same filesystem, so `exe` is never left
+/// half-written. The running process keeps executing the replaced file.
+pub(crate) fn replace_executable(new_path: &str, exe: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(new_path, std::fs::Permissions::from_mode(0o755))
+            .context("Failed to make new executable executable")?;
+    }
+    std::fs::rename(new_path, exe)
+        .with_context(|| format!("Failed to replace {}", exe.display()))?;
+    Ok(())
+}
+
+/// Execute `exe` in place of the current process with the same arguments.
+///
+/// Only returns if the exec failed.
+#[cfg(unix)]
+pub(crate) fn reexec(exe: &Path) -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec()
+}
+
+/// Wait until the process receives SIGINT or SIGTERM (only Ctrl-C on non-Unix platforms).
+pub(crate) async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(err) => {
+                error!("Failed to install SIGTERM handler: {}", err);
+                _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Exponential backoff with random jitter for retry loops.
+pub(crate) struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    /// Get the delay before the next attempt, doubling the delay for the one after it.
+    ///
+    /// Up to a quarter of the delay is added as jitter so agents don't retry in lockstep.
+    pub(crate) fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay + delay.mul_f64(rand::random::<f64>() / 4.0)
+    }
+
+    /// Start over from the base delay, e.g. after a successful connection.
+    pub(crate) fn reset(&mut self) {
+        self.current = self.base;
+    }
+}
+
+/// Transfer progress callback, receiving transferred bytes and total bytes if known.
+pub(crate) type ProgressCallback = Box<dyn FnMut(u64, Option<u64>) + Send>;
+
+/// Error returned when a downloaded file does not match the expected SHA-256 digest.
+#[derive(Debug)]
+pub(crate) struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch: expected {}, got {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Error returned when a downloaded file is not signed by the configured public key.
+#[derive(Debug)]
+pub(crate) struct UnauthorizedArtifact {
+    pub path: String,
+}
+
+impl std::fmt::Display for UnauthorizedArtifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Unauthorized artifact: {} is not signed by the configured key",
+            self.path
+        )
+    }
+}
+
+impl std::error::Error for UnauthorizedArtifact {}
+
+/// Parse an Ed25519 public key given as base64 of its 32 bytes.
+pub(crate) fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+    let bytes = STANDARD
+        .decode(key.trim())
+        .context("Public key is not valid base64")?;
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = bytes
+        .try_into()
+        .map_err(|_| anyhow!("Public key must be {} bytes", PUBLIC_KEY_LENGTH))?;
+    VerifyingKey::from_bytes(&bytes).context("Invalid Ed25519 public key")
+}
+
+/// Check that `signature` is an Ed25519ph signature (RFC 8032, SHA-512 prehash, no context)
+/// of the file at `path` made with the private part of `key`.
+///
+/// The file is hashed while read on a blocking thread, so files of any size can be checked
+/// without holding up the runtime.
+pub(crate) async fn verify_file_signature(
+    path: &str,
+    signature: &[u8],
+    key: &VerifyingKey,
+) -> Result<()> {
+    let unauthorized = || UnauthorizedArtifact {
+        path: path.to_string(),
+    };
+    let signature = Signature::from_slice(signature).map_err(|_| unauthorized())?;
+    let (hashed, key) = (path.to_string(), *key);
+    let verified = tokio::task::spawn_blocking(move || -> Result<bool> {
+        let mut hasher = Sha512::new();
+        hash_file(&hashed, &mut hasher)?;
+        Ok(key.verify_prehashed(hasher, None, &signature).is_ok())
+    })
+    .await??;
+    if !verified {
+        return Err(unauthorized().into());
+    }
+    Ok(())
+}
+
+/// Decode a detached signature given either as its raw 64 bytes or as base64.
+pub(crate) fn decode_signature(signature: &[u8]) -> Result<Vec<u8>> {
+    if signature.len() == SIGNATURE_LENGTH {
+        return Ok(signature.to_vec());
+    }
+    STANDARD
+        .decode(signature.trim_ascii())
+        .context("Signature is neither raw bytes nor base64")
+}
+
+/// Fetch the detached signature of a download from `url`.
+pub(crate) async fn fetch_signature(
+    client: &reqwest::Client,
+    url: &str,
+    headers: Option<&HashMap<String, String>>,
+) -> Result<Vec<u8>> {
+    let response = client
+        .get(url)
+        .headers(build_headers(url, headers)?)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(HttpStatusError {
+            url: url.to_string(),
+            status: response.status(),
+        }
+        .into());
+    }
+    let body = response.bytes().await?;
+    // Signatures are tiny, anything large is the wrong file
+    if body.len() > MAX_SIGNATURE_BYTES {
+        anyhow::bail!(
+            "Signature from {} is larger than {} bytes",
+            url,
+            MAX_SIGNATURE_BYTES
+        );
+    }
+    decode_signature(&body)
+}
+
+/// Largest signature file accepted from a `signature_url`.
+const MAX_SIGNATURE_BYTES: usize = 1024;
+
+/// Error returned when a download would not fit on the target filesystem.
+#[derive(Debug)]
+pub(crate) struct InsufficientDiskSpace {
+    pub path: String,
+    pub required: u64,
+    pub available: u64,
+}
+
+impl std::fmt::Display for InsufficientDiskSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Insufficient disk space for {}: {} bytes required, {} bytes available",
+            self.path, self.required, self.available
+        )
+    }
+}
+
+impl std::error::Error for InsufficientDiskSpace {}
+
+/// Fail when `required` more bytes would not fit on the filesystem that will hold `path`.
+///
+/// Skipped with a warning when the free space can't be determined.
+fn check_disk_space(path: &str, required: u64) -> Result<(), InsufficientDiskSpace> {
+    // The file may not exist yet, so look at the directory it goes into
+    let dir = match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    };
+    match available_space(&dir) {
+        Ok(available) if available < required => Err(InsufficientDiskSpace {
+            path: path.to_string(),
+            required,
+            available,
+        }),
+        Ok(_) => Ok(()),
+        Err(err) => {
+            warn!("Failed to get free disk space of {}: {}", dir, err);
+            Ok(())
+        }
+    }
+}
+
+/// Compare a finished SHA-256 digest against the expected hex string (case-insensitive).
+pub(crate) fn verify_sha256(hasher: Sha256, expected: &str) -> Result<(), ChecksumMismatch> {
+    let actual = format!("{:x}", hasher.finalize());
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(ChecksumMismatch {
+            expected: expected.to_lowercase(),
+            actual,
+        })
+    }
+}
+
+/// Feed the content of an existing file into a hasher.
+pub(crate) fn hash_file<D: Digest>(path: &str, hasher: &mut D) -> Result<u64> {
+    let mut fd = File::open(path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total: u64 = 0;
+    loop {
+        let n = fd.read(&mut buf)?;
+        if n == 0 {
+            return Ok(total);
+        }
+        hasher.update(&buf[..n]);
+        total += n as u64;
+    }
+}
+
+/// Error returned when a server answers a transfer with a non-success status.
+#[derive(Debug)]
+pub(crate) struct HttpStatusError {
+    pub url: String,
+    pub status: StatusCode,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server returned {} for {}", self.status, self.url)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// How often and after how long a failed transfer is retried.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// Attempts after the first one, 0 to never retry
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Whether `err` is worth another attempt: connection problems and 5xx responses are,
+    /// 4xx responses and local errors are not.
+    fn is_retryable(err: &anyhow::Error) -> bool {
+        if let Some(err) = err.downcast_ref::<HttpStatusError>() {
+            return err.status.is_server_error();
+        }
+        if let Some(err) = err.downcast_ref::<reqwest::Error>() {
+            // Breaking off the body is a decode error to reqwest, caught by `ConnectionDropped`
+            if err.is_connect() || err.is_timeout() || err.is_request() || err.is_body() {
+                return true;
+            }
+        }
+        err.is::<TransferStalled>() || err.is::<ConnectionDropped>()
+    }
+}
+
+/// Options controlling how `download_file` fetches and stores a file.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct DownloadOptions<'a> {
+    /// Expected SHA-256 digest, the file is removed when it does not match
+    pub sha256: Option<&'a str>,
+    /// Continue an existing partial file with a `Range` request
+    pub resume: bool,
+    /// Retries of failed attempts, which always resume what was already written
+    pub retry: RetryPolicy,
+    /// Fail when no data is received for this long
+    pub read_timeout: Option<Duration>,
+    /// Maximum average speed in bytes per second
+    pub max_bytes_per_sec: Option<u64>,
+    /// Decompress the downloaded bytes before writing them, which rules out resuming
+    pub decompress: Option<Compression>,
+    /// Fail when the file written grows larger than this
+    pub max_bytes: Option<u64>,
+    /// Extra request headers
+    pub headers: Option<&'a HashMap<String, String>>,
+    /// Permissions of the file written as an octal string like `0755`, only applied on Unix
+    pub mode: Option<&'a str>,
+    /// Bytes collected before writing them to disk, every chunk is written on its own if 0
+    pub buffer_bytes: usize,
+    /// Directory of the partial file, next to the file written if unset
+    pub temp_dir: Option<&'a str>,
+}
+
+/// Error returned when a download grows larger than allowed.
+#[derive(Debug)]
+pub(crate) struct DownloadTooLarge {
+    pub max_bytes: u64,
+}
+
+impl std::fmt::Display for DownloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Download exceeds the limit of {} bytes", self.max_bytes)
+    }
+}
+
+impl std::error::Error for DownloadTooLarge {}
+
+/// Fail when a file of `size` bytes would be larger than `max_bytes`.
+fn check_download_size(size: u64, max_bytes: Option<u64>) -> Result<(), DownloadTooLarge> {
+    match max_bytes {
+        Some(max_bytes) if size > max_bytes => Err(DownloadTooLarge { max_bytes }),
+        _ => Ok(()),
+    }
+}
+
+/// Compression format of a downloaded file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Compression::Gzip),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Streaming decoder turning downloaded chunks into decompressed bytes.
+enum Decoder {
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+}
+
+impl Decoder {
+    fn new(compression: Compression) -> std::io::Result<Self> {
+        Ok(match compression {
+            Compression::Gzip => Decoder::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+        })
+    }
+
+    /// Feed compressed bytes, returning what could be decompressed so far.
+    fn decode(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => {
+                decoder.write_all(data)?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+            Decoder::Zstd(decoder) => {
+                decoder.write_all(data)?;
+                decoder.flush()?;
+                Ok(std::mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    /// Finish the stream, returning the remaining decompressed bytes.
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+/// Sleep-based limiter keeping the average speed of a transfer at or below a rate.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    started: tokio::time::Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            started: tokio::time::Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account for `bytes` just transferred, sleeping until the average is back within the rate.
+    async fn consume(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let due = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_sec as f64);
+        tokio::time::sleep_until(self.started + due).await;
+    }
+}
+
+/// Error returned when a transfer receives no data for too long.
+#[derive(Debug)]
+pub(crate) struct TransferStalled {
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TransferStalled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No data received in {} seconds", self.timeout.as_secs())
+    }
+}
+
+impl std::error::Error for TransferStalled {}
+
+/// Context of an error breaking off a download after the response began, as opposed to one
+/// failing the request itself. The underlying error stays available with `downcast_ref`.
+#[derive(Debug)]
+pub(crate) struct ConnectionDropped {
+    /// Bytes of the response body received, including resumed ones
+    pub received: u64,
+    pub total: Option<u64>,
+}
+
+impl std::fmt::Display for ConnectionDropped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Connection dropped at byte {}", self.received)?;
+        if let Some(total) = self.total {
+            write!(f, " of {}", total)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConnectionDropped {}
+
+/// Validate the extra request headers of a transfer, logging their names but not their values.
+fn build_headers(url: &str, headers: Option<&HashMap<String, String>>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    let Some(headers) = headers else {
+        return Ok(map);
+    };
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow!("Invalid header name: {:?}", name))?;
+        let mut value = HeaderValue::from_str(value)
+            .map_err(|_| anyhow!("Invalid value of header {}", name))?;
+        value.set_sensitive(true);
+        map.insert(name, value);
+    }
+    let names: Vec<&str> = map.keys().map(|name| name.as_str()).collect();
+    info!("Sending extra headers to {}: {}", url, names.join(", "));
+    Ok(map)
+}
+
+/// What a successful `download_file` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DownloadOutcome {
+    Downloaded,
+    /// `path` already held a file matching `sha256`, nothing was fetched
+    Skipped,
+}
+
+/// Whether the file at `path` exists and has the SHA-256 digest `expected`.
+async fn file_matches_sha256(path: &str, expected: &str) -> bool {
+    let (path, expected) = (path.to_string(), expected.to_string());
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        hash_file(&path, &mut hasher).is_ok() && verify_sha256(hasher, &expected).is_ok()
+    })
+    .await
+    .unwrap_or(false)
+}
+
 /// Download a file from the given URL and save it to the given path.
-pub(crate) async fn download_file(client: &reqwest::Client, url: &str, path: &str) -> Result<()> {
+///
+/// The file is written to `partial_path(path, temp_dir)` and moved to `path` once complete
+/// and verified, so `path` never holds a partial download. The partial file is removed when the
+/// download fails, unless `resume` is set to pick it up again later. Without `resume` a partial
+/// file left by an earlier download is removed before the first attempt.
+///
+/// With `resume` set and a partial file present, only the missing bytes are requested.
+/// A server ignoring the range with `200` causes the partial file to be truncated and
+/// downloaded from scratch.
+///
+/// With `decompress` set, the file is written decompressed while `sha256`, the disk space
+/// check and `progress` refer to the compressed bytes received. `max_bytes` always limits
+/// the size of the file written, which is removed when exceeding it.
+///
+/// With `sha256` set and no `decompress`, a file already at `path` with that digest is kept
+/// and the server is not contacted at all.
+///
+/// `mode` is applied before the file is moved to `path`, an invalid mode fails the download
+/// before anything is fetched.
+///
+/// `progress` is called after every chunk with the downloaded bytes and, if the server
+/// sent `Content-Length`, the total bytes.
+pub(crate) async fn download_file(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    options: DownloadOptions<'_>,
+    mut progress: Option<ProgressCallback>,
+) -> Result<DownloadOutcome> {
+    // Offsets in a decompressed file don't match the compressed bytes to request
+    let resumable = options.decompress.is_none();
+    let headers = build_headers(url, options.headers)?;
+    let mode = options.mode.map(parse_mode).transpose()?;
+    // The digest of a decompressed file can't be compared to the one of the compressed bytes
+    if let (Some(expected), None) = (options.sha256, options.decompress) {
+        if file_matches_sha256(path, expected).await {
+            info!("{} already has sha256 {}, skip download", path, expected);
+            if let Some(mode) = mode {
+                set_mode(path, mode).await?;
+            }
+            return Ok(DownloadOutcome::Skipped);
+        }
+    }
+    if !options.resume {
+        // Retries resume, but only the bytes written by this download
+        remove_partial_file(&partial_path(path, options.temp_dir));
+    }
+    let mut attempt = 0;
+    loop {
+        // Retries keep the bytes already written instead of fetching them again
+        let resume = resumable && (options.resume || attempt > 0);
+        let result = download_once(
+            client,
+            url,
+            path,
+            &options,
+            &headers,
+            resume,
+            progress.as_mut(),
+        )
+        .await;
+        match result {
+            Err(err) if attempt < options.retry.retries && RetryPolicy::is_retryable(&err) => {
+                attempt += 1;
+                warn!(
+                    "Download of {} failed: {}. Retry {}/{} in {} seconds...",
+                    url,
+                    err,
+                    attempt,
+                    options.retry.retries,
+                    options.retry.delay.as_secs()
+                );
+                tokio::time::sleep(options.retry.delay).await;
+            }
+            Err(err) => {
+                if !(resumable && options.resume) || err.is::<DownloadTooLarge>() {
+                    remove_partial_file(&partial_path(path, options.temp_dir));
+                }
+                return Err(err);
+            }
+            Ok(()) => return Ok(DownloadOutcome::Downloaded),
+        }
+    }
+}
+
+/// Parse a file mode given as an octal string like `0755` or `644`.
+pub(crate) fn parse_mode(mode: &str) -> Result<u32> {
+    let valid = (1..=5).contains(&mode.len()) && mode.bytes().all(|b| (b'0'..=b'7').contains(&b));
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|value| valid && *value <= 0o7777)
+        .ok_or_else(|| anyhow!("Invalid file mode: {:?}", mode))
+}
+
+/// Set the permissions of `path` to `mode`.
+#[cfg(unix)]
+async fn set_mode(path: &str, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .with_context(|| format!("Failed to set mode {:o} of {}", mode, path))
+}
+
+/// Set the permissions of `path` to `mode`.
+#[cfg(not(unix))]
+async fn set_mode(path: &str, mode: u32) -> Result<()> {
+    warn!(
+        "File modes are not supported on this platform, {:o} not applied to {}",
+        mode, path
+    );
+    Ok(())
+}
+
+/// Temporary file a download is written to before it's moved to `path`.
+///
+/// In `temp_dir` the name carries a digest of `path`, so downloads of equally named files to
+/// different directories don't share a partial file while a resumed download finds its own.
+pub(crate) fn partial_path(path: &str, temp_dir: Option<&str>) -> String {
+    let Some(temp_dir) = temp_dir else {
+        return format!("{}.part", path);
+    };
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let digest = format!("{:x}", Sha256::digest(path.as_bytes()));
+    Path::new(temp_dir)
+        .join(format!("{}.{}.part", name, &digest[..16]))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Move a finished partial file to `path`.
+///
+/// Across filesystems it's copied next to `path` first and renamed from there, so `path`
+/// never holds a partial copy either.
+async fn finish_partial(part: &str, path: &str) -> Result<()> {
+    match tokio::fs::rename(part, path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            let staged = partial_path(path, None);
+            info!("Copying {} to the filesystem of {}", part, path);
+            if let Err(err) = copy_file(part, &staged).await {
+                remove_partial_file(&staged);
+                return Err(err);
+            }
+            tokio::fs::rename(&staged, path).await?;
+            tokio::fs::remove_file(part).await?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Copy a downloaded file to a further destination `dst`, which is only replaced once the
+/// copy is complete.
+pub(crate) async fn copy_download(src: &str, dst: &str) -> Result<()> {
+    let part = partial_path(dst, None);
+    // Errors name `dst`, the partial file is an implementation detail to controller
+    if let Err(err) = tokio::fs::copy(src, &part).await {
+        remove_partial_file(&part);
+        return Err(fs_error("copy to", dst, err));
+    }
+    if let Err(err) = tokio::fs::rename(&part, dst).await {
+        remove_partial_file(&part);
+        return Err(fs_error("copy to", dst, err));
+    }
+    Ok(())
+}
+
+/// Remove a partially written file, if any.
+pub(crate) fn remove_partial_file(path: &str) {
+    if let Err(err) = std::fs::remove_file(path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove partial file {}: {}", path, err);
+        }
+    }
+}
+
+/// Describe a failed filesystem operation on `path`, spelling out the common causes.
+fn fs_error(op: &str, path: &str, err: std::io::Error) -> anyhow::Error {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => anyhow!("{} does not exist", path),
+        std::io::ErrorKind::PermissionDenied => anyhow!("Permission denied to {} {}", op, path),
+        _ => anyhow!("Failed to {} {}: {}", op, path, err),
+    }
+}
+
+/// Move the file or directory `src` to `dst`.
+///
+/// Files on another filesystem are copied and then removed, directories can only be moved
+/// within a filesystem.
+pub(crate) async fn move_path(src: &str, dst: &str) -> Result<()> {
+    match tokio::fs::rename(src, dst).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_file(src, dst).await?;
+            tokio::fs::remove_file(src)
+                .await
+                .map_err(|err| fs_error("remove", src, err))
+        }
+        Err(err) => Err(fs_error("move", src, err)),
+    }
+}
+
+/// Copy the file `src` to `dst`, replacing `dst` if it exists. Returns the bytes copied.
+pub(crate) async fn copy_file(src: &str, dst: &str) -> Result<u64> {
+    let metadata = tokio::fs::metadata(src)
+        .await
+        .map_err(|err| fs_error("copy", src, err))?;
+    if metadata.is_dir() {
+        anyhow::bail!("{} is a directory, only files can be copied", src);
+    }
+    tokio::fs::copy(src, dst)
+        .await
+        .map_err(|err| fs_error("copy to", dst, err))
+}
+
+/// Delete the file `path`, or the directory `path` if it's empty or `recursive` is set.
+pub(crate) async fn delete_path(path: &str, recursive: bool) -> Result<()> {
+    let metadata = tokio::fs::symlink_metadata(path)
+        .await
+        .map_err(|err| fs_error("delete", path, err))?;
+    let result = if !metadata.is_dir() {
+        tokio::fs::remove_file(path).await
+    } else if recursive {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_dir(path).await
+    };
+    result.map_err(|err| fs_error("delete", path, err))
+}
+
+/// Read the whole file `path`, failing without reading it if it's larger than `max_bytes`.
+pub(crate) async fn read_file(path: &str, max_bytes: u64) -> Result<Vec<u8>> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|err| fs_error("read", path, err))?;
+    let size = file.metadata().await?.len();
+    if size > max_bytes {
+        anyhow::bail!(
+            "{} has {} bytes, more than the limit of {} bytes",
+            path,
+            size,
+            max_bytes
+        );
+    }
+    // Files like those in /proc report no size, so the limit is enforced on reading as well
+    let mut content = Vec::new();
+    file.take(max_bytes + 1)
+        .read_to_end(&mut content)
+        .await
+        .map_err(|err| fs_error("read", path, err))?;
+    if content.len() as u64 > max_bytes {
+        anyhow::bail!("{} has more than the limit of {} bytes", path, max_bytes);
+    }
+    Ok(content)
+}
+
+async fn download_once(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    options: &DownloadOptions<'_>,
+    headers: &HeaderMap,
+    resume: bool,
+    mut progress: Option<&mut ProgressCallback>,
+) -> Result<()> {
     info!("Downloading file from {} to {}", url, path);
-    let mut response = client.get(url).send().await?;
+    let part = partial_path(path, options.temp_dir);
+    let existing = if resume {
+        tokio::fs::metadata(&part)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    let mut request = client.get(url).headers(headers.clone());
+    if existing > 0 {
+        info!("Resuming download of {} from byte {}", path, existing);
+        request = request.header(RANGE, format!("bytes={}-", existing));
+    }
+    let mut response = request.send().await?;
     if response.status().is_success() {
-        let mut out = File::create(path)?;
+        let resumed = existing > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+        if existing > 0 && !resumed {
+            warn!("Server ignored range request, restart download of {}", path);
+        }
+        if let Some(required) = response.content_length() {
+            if options.decompress.is_none() {
+                let size = required + if resumed { existing } else { 0 };
+                if let Err(err) = check_download_size(size, options.max_bytes) {
+                    error!("Download of {} is too large: {}", path, err);
+                    return Err(err.into());
+                }
+            }
+            if let Err(err) = check_disk_space(&part, required) {
+                error!("{}", err);
+                return Err(err.into());
+            }
+        }
+        let mut hasher = Sha256::new();
+        let (out, mut downloaded) = if resumed {
+            if options.sha256.is_some() {
+                // The bytes written before may be most of a large file
+                let prefix = part.clone();
+                hasher = tokio::task::spawn_blocking(move || -> Result<Sha256> {
+                    let mut hasher = Sha256::new();
+                    hash_file(&prefix, &mut hasher)?;
+                    Ok(hasher)
+                })
+                .await??;
+            }
+            (
+                tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&part)
+                    .await?,
+                existing,
+            )
+        } else {
+            // `File::create` truncates anything left from a previous attempt
+            (tokio::fs::File::create(&part).await?, 0)
+        };
+        let mut out = BufWriter::with_capacity(options.buffer_bytes, out);
+        let total = response.content_length().map(|len| len + downloaded);
+        let mut limiter = options.max_bytes_per_sec.map(RateLimiter::new);
+        let mut decoder = options.decompress.map(Decoder::new).transpose()?;
+        let mut written = downloaded;
         loop {
-            let chunk = response.chunk().await?;
-            if chunk.is_none() {
-                return Ok(());
+            let chunk = match options.read_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, response.chunk())
+                    .await
+                    .map_err(|_| TransferStalled { timeout }.into()),
+                None => Ok(response.chunk().await),
+            }
+            .and_then(|chunk| {
+                chunk.map_err(|err| {
+                    anyhow::Error::new(err).context(ConnectionDropped {
+                        received: downloaded,
+                        total,
+                    })
+                })
+            });
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    // A resumed attempt continues after the bytes written so far
+                    if let Err(flush_err) = out.flush().await {
+                        warn!("Failed to write partial file {}: {}", part, flush_err);
+                    }
+                    return Err(err);
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let decoded;
+            let data: &[u8] = if let Some(decoder) = decoder.as_mut() {
+                decoded = decoder.decode(&chunk)?;
+                &decoded
+            } else {
+                &chunk
+            };
+            written += data.len() as u64;
+            check_download_size(written, options.max_bytes)?;
+            out.write_all(data).await?;
+            hasher.update(&chunk);
+            downloaded += chunk.len() as u64;
+            metrics::downloaded(chunk.len() as u64);
+            if let Some(cb) = progress.as_mut() {
+                cb(downloaded, total);
+            }
+            if let Some(limiter) = limiter.as_mut() {
+                limiter.consume(chunk.len() as u64).await;
             }
-            out.write_all(&chunk.unwrap())?;
         }
+        if let Some(decoder) = decoder {
+            let data = decoder.finish()?;
+            check_download_size(written + data.len() as u64, options.max_bytes)?;
+            out.write_all(&data).await?;
+        }
+        // Writes the buffered bytes and waits for the writes of the tokio file, which complete
+        // in the background, before verifying
+        out.flush().await?;
+        drop(out);
+        if let Some(expected) = options.sha256 {
+            if let Err(err) = verify_sha256(hasher, expected) {
+                error!("Downloaded file {} is corrupted: {}", path, err);
+                tokio::fs::remove_file(&part).await?;
+                return Err(err.into());
+            }
+        }
+        if let Some(mode) = options.mode {
+            set_mode(&part, parse_mode(mode)?).await?;
+        }
+        finish_partial(&part, path).await?;
+        Ok(())
     } else {
         error!(
             "Failed to download file from {}. Server returned an error.",
             url
         );
-        anyhow::bail!("Failed to download file from {}", url);
+        Err(HttpStatusError {
+            url: url.to_string(),
+            status: response.status(),
+        }
+        .into())
     }
 }
 
+/// Options controlling how `upload_file` sends a file.
+#[derive(Debug, Default)]
+pub(crate) struct UploadOptions<'a> {
+    /// Send the file as a `multipart/form-data` part instead of the raw request body
+    pub multipart: bool,
+    /// Form field name of the file part, `file` if unset
+    pub field_name: Option<&'a str>,
+    /// Retries of failed attempts
+    pub retry: RetryPolicy,
+    /// Extra request headers
+    pub headers: Option<&'a HashMap<String, String>>,
+    /// Send a chunked body read until the end of the file, instead of as many bytes as the file
+    /// had when the upload started
+    pub tail: bool,
+    /// With `tail`, wait at the end of the file for more data until this long after the
+    /// upload started. Reading then stops at the size the file has at that moment, so a file
+    /// growing faster than it's sent can't keep the upload going forever
+    pub follow: Option<Duration>,
+}
+
+/// What was sent by a successful upload.
+#[derive(Debug)]
+pub(crate) struct UploadSummary {
+    /// Hex SHA-256 of the uploaded bytes
+    pub sha256: String,
+    pub size: u64,
+}
+
 /// Upload a file to the given URL.
-pub(crate) async fn upload_file(client: &reqwest::Client, url: &str, path: &str) -> Result<()> {
+///
+/// `progress` is called after every chunk sent with the bytes sent and the file size. A retry
+/// sends the file again from the start, so the bytes reported start over.
+pub(crate) async fn upload_file(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    options: UploadOptions<'_>,
+    progress: Option<ProgressCallback>,
+) -> Result<UploadSummary> {
+    let headers = build_headers(url, options.headers)?;
+    // The body stream outlives each request, so it shares the callback instead of borrowing it
+    let progress = progress.map(|progress| Arc::new(Mutex::new(progress)));
+    let mut attempt = 0;
+    loop {
+        match upload_once(client, url, path, &options, &headers, progress.clone()).await {
+            Err(err) if attempt < options.retry.retries && RetryPolicy::is_retryable(&err) => {
+                attempt += 1;
+                warn!(
+                    "Upload to {} failed: {}. Retry {}/{} in {} seconds...",
+                    url,
+                    err,
+                    attempt,
+                    options.retry.retries,
+                    options.retry.delay.as_secs()
+                );
+                tokio::time::sleep(options.retry.delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn upload_once(
+    client: &reqwest::Client,
+    url: &str,
+    path: &str,
+    options: &UploadOptions<'_>,
+    headers: &HeaderMap,
+    progress: Option<Arc<Mutex<ProgressCallback>>>,
+) -> Result<UploadSummary> {
     info!("Uploading file from {} to {}", path, url);
     let file = File::open(path)?;
-    let req = client
-        .post(url)
-        .body(tokio::fs::File::from_std(file))
-        .send()
-        .await?;
+    let length = file.metadata()?.len();
+    let file = tokio::fs::File::from_std(file);
+    // Hash the body while it is sent, so the file is read only once
+    let digest = Arc::new(Mutex::new((Sha256::new(), 0u64)));
+    let (body, length) = if options.tail {
+        let reader = TailReader {
+            file,
+            path: path.to_string(),
+            deadline: Instant::now() + options.follow.unwrap_or_default(),
+            wait: None,
+            read: 0,
+            limit: None,
+        };
+        (hashing_stream(reader, digest.clone()).left_stream(), None)
+    } else {
+        let body = hashing_stream(file.take(length), digest.clone()).right_stream();
+        (body, Some(length))
+    };
+    let mut sent = 0u64;
+    let body = body.inspect(move |chunk| {
+        if let (Ok(chunk), Some(progress)) = (chunk, progress.as_ref()) {
+            sent += chunk.len() as u64;
+            (progress.lock().unwrap())(sent, length);
+        }
+    });
+    let request = client.post(url).headers(headers.clone());
+    let request = if options.multipart {
+        let file_name = std::path::Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".to_string());
+        let part = match length {
+            Some(length) => Part::stream_with_length(Body::wrap_stream(body), length),
+            None => Part::stream(Body::wrap_stream(body)),
+        };
+        let part = part.file_name(file_name);
+        request.multipart(Form::new().part(options.field_name.unwrap_or("file").to_string(), part))
+    } else {
+        request.body(Body::wrap_stream(body))
+    };
+    let req = request.send().await?;
     if req.status().is_success() {
-        Ok(())
+        let (hasher, size) = std::mem::take(&mut *digest.lock().unwrap());
+        Ok(UploadSummary {
+            sha256: format!("{:x}", hasher.finalize()),
+            size,
+        })
     } else {
         error!(
             "Failed to upload file to {}. Server returned an error.",
             url
         );
-        anyhow::bail!("Failed to upload file to {}", url);
+        Err(HttpStatusError {
+            url: url.to_string(),
+            status: req.status(),
+        }
+        .into())
+    }
+}
+
+/// Time between checks for more data of a followed file.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Reader of a file that may still be written to, waiting at its end for more data until
+/// `deadline`, after which it stops at the size the file has then.
+struct TailReader {
+    file: tokio::fs::File,
+    path: String,
+    deadline: Instant,
+    /// Wait before looking for more data at the end of the file
+    wait: Option<Pin<Box<tokio::time::Sleep>>>,
+    read: u64,
+    /// Size reading stops at, taken once `deadline` passed
+    limit: Option<u64>,
+}
+
+impl AsyncRead for TailReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if let Some(wait) = this.wait.as_mut() {
+                ready!(wait.as_mut().poll(cx));
+                this.wait = None;
+            }
+            if this.limit.is_none() && Instant::now() >= this.deadline {
+                this.limit = Some(std::fs::metadata(&this.path)?.len());
+            }
+            let remaining = this.limit.map(|limit| limit.saturating_sub(this.read));
+            if remaining == Some(0) {
+                return Poll::Ready(Ok(()));
+            }
+            let max = remaining.map_or(buf.remaining(), |remaining| {
+                buf.remaining()
+                    .min(usize::try_from(remaining).unwrap_or(usize::MAX))
+            });
+            let mut limited = buf.take(max);
+            ready!(Pin::new(&mut this.file).poll_read(cx, &mut limited))?;
+            let n = limited.filled().len();
+            if n > 0 {
+                // SAFETY: `take` hands out the unfilled part of `buf`, which `poll_read` filled
+                unsafe { buf.assume_init(n) };
+                buf.advance(n);
+                this.read += n as u64;
+                return Poll::Ready(Ok(()));
+            }
+            if this.limit.is_some() {
+                // Truncated below the size taken at the deadline
+                return Poll::Ready(Ok(()));
+            }
+            let wake = (Instant::now() + TAIL_POLL_INTERVAL).min(this.deadline);
+            this.wait = Some(Box::pin(tokio::time::sleep_until(wake)));
+        }
+    }
+}
+
+/// Stream a reader in chunks, feeding every chunk into `digest` along with the byte count.
+fn hashing_stream<R: AsyncRead + Unpin + Send + 'static>(
+    reader: R,
+    digest: Arc<Mutex<(Sha256, u64)>>,
+) -> impl futures_util::Stream<Item = std::io::Result<Vec<u8>>> {
+    futures_util::stream::try_unfold(reader, move |mut reader| {
+        let digest = digest.clone();
+        async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            buf.truncate(n);
+            let mut digest = digest.lock().unwrap();
+            digest.0.update(&buf);
+            digest.1 += n as u64;
+            metrics::uploaded(n as u64);
+            Ok(Some((buf, reader)))
+        }
+    })
+}
+
+/// Error returned when an external command does not finish in time.
+#[derive(Debug)]
+pub(crate) struct CommandTimeout {
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Command timed out after {} seconds",
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for CommandTimeout {}
+
+/// How an external command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CommandExit {
+    /// Exited on its own with this code
+    Code(i32),
+    /// Killed by this signal
+    Signal(i32),
+}
+
+impl CommandExit {
+    fn from_status(status: std::process::ExitStatus) -> Result<Self> {
+        if let Some(code) = status.code() {
+            return Ok(CommandExit::Code(code));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return Ok(CommandExit::Signal(signal));
+            }
+        }
+        anyhow::bail!("Unknown exit status: {}", status)
+    }
+
+    /// Exit code, `128 + signal` for killed commands like shells report them.
+    pub(crate) fn code(&self) -> i32 {
+        match self {
+            CommandExit::Code(code) => *code,
+            CommandExit::Signal(signal) => 128 + signal,
+        }
+    }
+
+    pub(crate) fn signal(&self) -> Option<i32> {
+        match self {
+            CommandExit::Code(_) => None,
+            CommandExit::Signal(signal) => Some(*signal),
+        }
     }
 }
 
+/// Options controlling how an external command is spawned.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct CommandOptions<'a> {
+    /// Kill the command when it runs longer than this
+    pub timeout: Option<Duration>,
+    /// Working directory, inherited from the agent if unset
+    pub cwd: Option<&'a str>,
+    /// Extra environment variables
+    pub env: Option<&'a HashMap<String, String>>,
+    /// Start from an empty environment instead of inheriting the agent's one
+    pub env_clear: bool,
+    /// Bytes written to the command's stdin, which is closed afterwards. Stdin is empty if unset
+    pub stdin: Option<&'a str>,
+}
+
+/// Build a `Command` with the given options applied.
+fn build_command(cmd: &String, args: Vec<String>, options: CommandOptions<'_>) -> Result<Command> {
+    let mut command = Command::new(cmd);
+    // Cancelled or aborted tasks must not leave the process behind
+    command.args(args).kill_on_drop(true);
+    command.stdin(if options.stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    if let Some(cwd) = options.cwd {
+        if !std::path::Path::new(cwd).is_dir() {
+            anyhow::bail!("Working directory {} does not exist", cwd);
+        }
+        command.current_dir(cwd);
+    }
+    if options.env_clear {
+        command.env_clear();
+    }
+    if let Some(env) = options.env {
+        if let Some(key) = env.keys().find(|k| k.is_empty() || k.contains('=')) {
+            anyhow::bail!("Invalid environment variable name: {:?}", key);
+        }
+        command.envs(env);
+    }
+    Ok(command)
+}
+
 /// Execute an external command. Ignore **ALL** stdio.
-pub(crate) async fn execute_command(cmd: &String, args: Vec<String>) -> Result<i32> {
+pub(crate) async fn execute_command(
+    cmd: &String,
+    args: Vec<String>,
+    options: CommandOptions<'_>,
+) -> Result<CommandExit> {
     info!("Executing external command: {} {:?}", cmd, args);
-    if let Some(code) = Command::new(cmd)
-        .args(args)
-        .stdin(Stdio::null())
+    let mut child = build_command(cmd, args, options)?
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .status()
-        .await?
-        .code()
-    {
-        Ok(code)
+        .spawn()
+        .map_err(|err| spawn_error(cmd, err))?;
+    write_stdin(&mut child, options);
+    let status = if let Some(timeout) = options.timeout {
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                error!("Command {} timed out, killing it", cmd);
+                child.kill().await?;
+                return Err(CommandTimeout { timeout }.into());
+            }
+        }
     } else {
-        error!("Failed to execute command: {}", cmd);
-        anyhow::bail!("Failed to execute command: {}", cmd);
+        child.wait().await?
+    };
+    CommandExit::from_status(status)
+}
+
+/// Describe a failure to start `cmd`, naming a missing program as such.
+fn spawn_error(cmd: &str, err: std::io::Error) -> anyhow::Error {
+    if err.kind() == std::io::ErrorKind::NotFound {
+        anyhow!("Command not found: {}", cmd)
+    } else {
+        err.into()
     }
 }
 
-/// Execute a command with sh wrapped. Ignore **ALL** stdio.
-pub(crate) async fn execute_shell(cmd: &String) -> Result<i32> {
-    execute_command(&("sh".to_string()), vec!["-c".to_string(), cmd.to_string()]).await
+/// Feed `options.stdin` to a spawned command, closing its stdin once written.
+///
+/// Written from a separate task, so a command that produces output before reading all of
+/// its input can't block on a full pipe while the agent waits for it to take the input.
+fn write_stdin(child: &mut Child, options: CommandOptions<'_>) {
+    let (Some(input), Some(mut stdin)) = (options.stdin, child.stdin.take()) else {
+        return;
+    };
+    let input = input.as_bytes().to_vec();
+    tokio::spawn(async move {
+        // Commands may exit without reading their input, which isn't an error of the task
+        if let Err(err) = stdin.write_all(&input).await {
+            debug!("Failed to write stdin of command: {}", err);
+        }
+    });
+}
+
+/// Shell running the command line of an execute task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Shell {
+    Sh,
+    Bash,
+    /// `powershell` on Windows, PowerShell Core `pwsh` elsewhere
+    Powershell,
 }
 
-/// Execute an external command and print its output.
+impl Default for Shell {
+    /// `sh` on Unix, PowerShell on Windows.
+    fn default() -> Self {
+        if cfg!(windows) {
+            Shell::Powershell
+        } else {
+            Shell::Sh
+        }
+    }
+}
+
+impl Shell {
+    /// Parse the `shell` of an execute event.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sh" => Some(Shell::Sh),
+            "bash" => Some(Shell::Bash),
+            "powershell" | "pwsh" => Some(Shell::Powershell),
+            _ => None,
+        }
+    }
+
+    /// Program and arguments running the command line `cmd` with this shell.
+    pub(crate) fn command(self, cmd: &str) -> (String, Vec<String>) {
+        let (program, flags): (&str, &[&str]) = match self {
+            Shell::Sh => ("sh", &["-c"]),
+            Shell::Bash => ("bash", &["-c"]),
+            Shell::Powershell if cfg!(windows) => {
+                ("powershell", &["-NoProfile", "-NonInteractive", "-Command"])
+            }
+            Shell::Powershell => ("pwsh", &["-NoProfile", "-NonInteractive", "-Command"]),
+        };
+        let mut args: Vec<String> = flags.iter().map(|flag| flag.to_string()).collect();
+        args.push(cmd.to_string());
+        (program.to_string(), args)
+    }
+}
+
+/// Run an external command and upload its stdout as the request body to the given URL,
+/// without storing it on disk. Stderr is ignored.
+///
+/// Returns how the command ended together with the upload result. A failed upload kills the
+/// command, so its exit reflects that. Never retried, since the output can't be replayed.
+pub(crate) async fn upload_command_output(
+    client: &reqwest::Client,
+    url: &str,
+    cmd: &String,
+    args: Vec<String>,
+    options: CommandOptions<'_>,
+) -> Result<(CommandExit, Result<UploadSummary>)> {
+    info!("Uploading output of {} {:?} to {}", cmd, args, url);
+    let mut child = build_command(cmd, args, options)?
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| spawn_error(cmd, err))?;
+    write_stdin(&mut child, options);
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Failed to open stdout"))?;
+    let digest = Arc::new(Mutex::new((Sha256::new(), 0u64)));
+    let body = Body::wrap_stream(hashing_stream(stdout, digest.clone()));
+
+    let run = async {
+        let upload = match client.post(url).body(body).send().await {
+            Ok(response) if response.status().is_success() => {
+                let (hasher, size) = std::mem::take(&mut *digest.lock().unwrap());
+                Ok(UploadSummary {
+                    sha256: format!("{:x}", hasher.finalize()),
+                    size,
+                })
+            }
+            Ok(response) => Err(HttpStatusError {
+                url: url.to_string(),
+                status: response.status(),
+            }
+            .into()),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = &upload {
+            error!("Failed to upload output of {} to {}: {}", cmd, url, err);
+            child.kill().await?;
+        }
+        let status = child.wait().await?;
+        Ok::<_, anyhow::Error>((CommandExit::from_status(status)?, upload))
+    };
+    if let Some(timeout) = options.timeout {
+        match tokio::time::timeout(timeout, run).await {
+            Ok(result) => result,
+            Err(_) => {
+                error!("Command {} timed out, killing it", cmd);
+                child.kill().await?;
+                Err(CommandTimeout { timeout }.into())
+            }
+        }
+    } else {
+        run.await
+    }
+}
+
+/// Standard stream an output line was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Command output callback, receiving every line together with the stream it came from.
+pub(crate) type OutputCallback = Box<dyn FnMut(OutputStream, String) + Send>;
+
+/// Execute an external command and pass each line of its output to `callback`.
+///
+/// Returns how the command ended once both stdout and stderr are closed and the process exited.
 pub(crate) async fn execute_command_with_callback(
     cmd: &String,
     args: Vec<String>,
-    mut callback: Box<dyn FnMut(String)>,
-) -> Result<()> {
+    options: CommandOptions<'_>,
+    mut callback: OutputCallback,
+) -> Result<CommandExit> {
     info!("Executing external command: {} {:?}", cmd, args);
-    let mut child = Command::new(cmd)
-        .args(args)
-        .stdin(Stdio::null())
+    let mut child = build_command(cmd, args, options)?
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?;
+        .spawn()
+        .map_err(|err| spawn_error(cmd, err))?;
+    write_stdin(&mut child, options);
     let stdout = child
         .stdout
         .take()
@@ -111,41 +1613,486 @@ pub(crate) async fn execute_command_with_callback(
         .ok_or_else(|| anyhow!("Failed to open stderr"))?;
     let mut stdout_reader = BufReader::new(stdout).lines();
     let mut stderr_reader = BufReader::new(stderr).lines();
+    let mut stdout_closed = false;
+    let mut stderr_closed = false;
 
-    loop {
-        select! {
-            line = stdout_reader.next_line() => {
-                let line = line?;
-                if let Some(line) = line {
-                    callback(line);
-                } else {
-                    break;
-                }
-            },
-            line = stderr_reader.next_line() => {
-                let line = line?;
-                if let Some(line) = line {
-                    callback(line);
-                } else {
-                    break;
+    let run = async {
+        while !(stdout_closed && stderr_closed) {
+            select! {
+                line = stdout_reader.next_line(), if !stdout_closed => {
+                    if let Some(line) = line? {
+                        callback(OutputStream::Stdout, line);
+                    } else {
+                        stdout_closed = true;
+                    }
+                },
+                line = stderr_reader.next_line(), if !stderr_closed => {
+                    if let Some(line) = line? {
+                        callback(OutputStream::Stderr, line);
+                    } else {
+                        stderr_closed = true;
+                    }
                 }
             }
         }
+        Ok::<_, anyhow::Error>(child.wait().await?)
+    };
+    let status = if let Some(timeout) = options.timeout {
+        match tokio::time::timeout(timeout, run).await {
+            Ok(status) => status?,
+            Err(_) => {
+                error!("Command {} timed out, killing it", cmd);
+                child.kill().await?;
+                return Err(CommandTimeout { timeout }.into());
+            }
+        }
+    } else {
+        run.await?
+    };
+    CommandExit::from_status(status)
+}
+
+/// Cut `s` to at most `max_bytes`, backing off to the previous char boundary.
+pub(crate) fn truncate_str(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Exit status and captured output of an external command.
+#[derive(Debug)]
+pub(crate) struct CommandOutput {
+    pub exit: CommandExit,
+    /// Combined stdout and stderr, one line per output line
+    pub output: String,
+    /// Whether output was cut off at the size limit
+    pub truncated: bool,
+}
+
+/// Exit status of an external command with its stdout and stderr captured apart.
+#[derive(Debug)]
+pub(crate) struct SplitCommandOutput {
+    pub exit: CommandExit,
+    pub stdout: String,
+    pub stderr: String,
+    /// Whether output was cut off at the size limit
+    pub truncated: bool,
+}
+
+/// Output lines collected up to a byte limit shared by all buffers.
+struct OutputBuffers {
+    buffers: [String; 2],
+    /// Bytes still allowed across all buffers
+    remaining: usize,
+    truncated: bool,
+}
+
+impl OutputBuffers {
+    fn new(max_bytes: usize) -> Self {
+        OutputBuffers {
+            buffers: Default::default(),
+            remaining: max_bytes,
+            truncated: false,
+        }
+    }
+
+    /// Append `line` to buffer `index`, cutting it off once the limit is reached.
+    fn push(&mut self, index: usize, line: &str) {
+        if self.truncated {
+            return;
+        }
+        let output = &mut self.buffers[index];
+        let separator = usize::from(!output.is_empty());
+        if separator + line.len() > self.remaining {
+            if self.remaining >= separator {
+                output.push_str(&"\n"[..separator]);
+                output.push_str(truncate_str(line, self.remaining - separator));
+            }
+            self.truncated = true;
+            return;
+        }
+        output.push_str(&"\n"[..separator]);
+        output.push_str(line);
+        self.remaining -= separator + line.len();
     }
-    child.wait().await?;
-    Ok(())
 }
 
 /// Execute an external command and return its output.
-pub(crate) async fn execute_command_with_output<'a>(
+///
+/// Lines of stdout and stderr are merged in the order they are read, which may not be the
+/// order the command wrote them in. At most `max_bytes` of output are kept, anything beyond
+/// is dropped and reported through `CommandOutput::truncated`.
+pub(crate) async fn execute_command_with_output(
     cmd: &String,
     args: Vec<String>,
-) -> Result<String> {
-    let mut buffer = Box::new(Vec::<String>::new());
-    let outputs = buffer.clone();
-    let cb = Box::new(move |output: String| {
-        buffer.push(output);
+    options: CommandOptions<'_>,
+    max_bytes: usize,
+) -> Result<CommandOutput> {
+    let (exit, [output, _], truncated) =
+        collect_output(cmd, args, options, max_bytes, |_| 0).await?;
+    Ok(CommandOutput {
+        exit,
+        output,
+        truncated,
+    })
+}
+
+/// Execute an external command and return its stdout and stderr apart.
+///
+/// At most `max_bytes` of output are kept for both streams together, anything beyond is
+/// dropped and reported through `SplitCommandOutput::truncated`.
+pub(crate) async fn execute_command_with_split_output(
+    cmd: &String,
+    args: Vec<String>,
+    options: CommandOptions<'_>,
+    max_bytes: usize,
+) -> Result<SplitCommandOutput> {
+    let (exit, [stdout, stderr], truncated) =
+        collect_output(cmd, args, options, max_bytes, |stream| match stream {
+            OutputStream::Stdout => 0,
+            OutputStream::Stderr => 1,
+        })
+        .await?;
+    Ok(SplitCommandOutput {
+        exit,
+        stdout,
+        stderr,
+        truncated,
+    })
+}
+
+/// Run a command collecting its output lines into the buffer `buffer_of` their stream.
+async fn collect_output(
+    cmd: &String,
+    args: Vec<String>,
+    options: CommandOptions<'_>,
+    max_bytes: usize,
+    buffer_of: fn(OutputStream) -> usize,
+) -> Result<(CommandExit, [String; 2], bool)> {
+    let buffers = Arc::new(Mutex::new(OutputBuffers::new(max_bytes)));
+    let outputs = buffers.clone();
+    let cb = Box::new(move |stream: OutputStream, line: String| {
+        buffers.lock().unwrap().push(buffer_of(stream), &line);
     });
-    execute_command_with_callback(cmd, args, cb).await?;
-    Ok(outputs.join("\n"))
+    let exit = execute_command_with_callback(cmd, args, options, cb).await?;
+    let mut outputs = outputs.lock().unwrap();
+    Ok((
+        exit,
+        std::mem::take(&mut outputs.buffers),
+        outputs.truncated,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// SHA-256 digest of `b"hello world"`.
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    /// Empty directory for the files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("metalx-{}-{}", name, std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Raw HTTP response with a body of `body`.
+    fn response(status: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// Serve `responses` in order, one per connection, returning the URL served and the count
+    /// of requests received.
+    async fn serve(responses: Vec<Vec<u8>>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    head.extend_from_slice(&buf[..n]);
+                }
+                stream.write_all(&response).await.unwrap();
+                _ = stream.shutdown().await;
+            }
+        });
+        (url, requests)
+    }
+
+    fn client() -> reqwest::Client {
+        reqwest::Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[test]
+    fn verify_sha256_matches_digest() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        assert!(verify_sha256(hasher.clone(), HELLO_SHA256).is_ok());
+        assert!(verify_sha256(hasher, &HELLO_SHA256.to_uppercase()).is_ok());
+    }
+
+    #[test]
+    fn verify_sha256_rejects_wrong_digest() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello world");
+        let err = verify_sha256(hasher, &"0".repeat(64)).unwrap_err();
+        assert_eq!(err.expected, "0".repeat(64));
+        assert_eq!(err.actual, HELLO_SHA256);
+    }
+
+    #[tokio::test]
+    async fn download_with_wrong_sha256_removes_file() {
+        let dir = temp_dir("checksum-mismatch");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let (url, _) = serve(vec![response("200 OK", b"hello world")]).await;
+        let wrong = "0".repeat(64);
+        let options = DownloadOptions {
+            sha256: Some(&wrong),
+            ..Default::default()
+        };
+        let err = download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap_err();
+        assert!(err.is::<ChecksumMismatch>());
+        assert!(!Path::new(&path).exists());
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn download_with_matching_sha256_writes_file() {
+        let dir = temp_dir("checksum-match");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let (url, _) = serve(vec![response("200 OK", b"hello world")]).await;
+        let options = DownloadOptions {
+            sha256: Some(HELLO_SHA256),
+            ..Default::default()
+        };
+        download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn retry(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            delay: Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn download_retries_server_errors() {
+        let dir = temp_dir("retry-5xx");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let unavailable = response("503 Service Unavailable", b"");
+        let (url, requests) = serve(vec![
+            unavailable.clone(),
+            unavailable,
+            response("200 OK", b"hello world"),
+        ])
+        .await;
+        let options = DownloadOptions {
+            sha256: Some(HELLO_SHA256),
+            retry: retry(3),
+            ..Default::default()
+        };
+        download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn download_retries_ignore_stale_partial_file() {
+        let dir = temp_dir("retry-stale-part");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        std::fs::write(partial_path(&path, None), b"stale").unwrap();
+        // A retry asking for a range would get the bytes following the stale ones
+        let (url, _) = serve(vec![
+            response("503 Service Unavailable", b""),
+            response("206 Partial Content", b"hello world"),
+        ])
+        .await;
+        let options = DownloadOptions {
+            retry: retry(1),
+            ..Default::default()
+        };
+        download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn download_gives_up_after_retries() {
+        let dir = temp_dir("retry-exhausted");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let unavailable = response("503 Service Unavailable", b"");
+        let (url, requests) = serve(vec![unavailable; 3]).await;
+        let options = DownloadOptions {
+            retry: retry(2),
+            ..Default::default()
+        };
+        let err = download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<HttpStatusError>().unwrap();
+        assert_eq!(err.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn download_does_not_retry_client_errors() {
+        let dir = temp_dir("retry-4xx");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let (url, requests) = serve(vec![
+            response("404 Not Found", b""),
+            response("200 OK", b"hello world"),
+        ])
+        .await;
+        let options = DownloadOptions {
+            retry: retry(3),
+            ..Default::default()
+        };
+        let err = download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<HttpStatusError>().unwrap();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(!Path::new(&path).exists());
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    /// Response announcing 100 bytes but closing the connection after 11.
+    fn truncated_response() -> Vec<u8> {
+        b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\nhello world".to_vec()
+    }
+
+    #[tokio::test]
+    async fn truncated_download_keeps_partial_file_to_resume() {
+        let dir = temp_dir("truncated-resume");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let (url, _) = serve(vec![truncated_response()]).await;
+        let options = DownloadOptions {
+            resume: true,
+            ..Default::default()
+        };
+        let err = download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap_err();
+        let dropped = err.downcast_ref::<ConnectionDropped>().unwrap();
+        assert_eq!(dropped.received, 11);
+        assert_eq!(dropped.total, Some(100));
+        assert!(!Path::new(&path).exists());
+        assert_eq!(
+            std::fs::read(partial_path(&path, None)).unwrap(),
+            b"hello world"
+        );
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn truncated_download_removes_partial_file() {
+        let dir = temp_dir("truncated-remove");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        let (url, _) = serve(vec![truncated_response()]).await;
+        let err = download_file(&client(), &url, &path, DownloadOptions::default(), None)
+            .await
+            .unwrap_err();
+        assert!(err.is::<ConnectionDropped>());
+        assert!(!Path::new(&path).exists());
+        assert!(!Path::new(&partial_path(&path, None)).exists());
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn verify_file_signature_checks_file_content() {
+        let dir = temp_dir("signature");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        std::fs::write(&path, b"hello world").unwrap();
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let mut prehash = Sha512::new();
+        prehash.update(b"hello world");
+        let signature = signing_key
+            .sign_prehashed(prehash, None)
+            .unwrap()
+            .to_bytes();
+        let key = signing_key.verifying_key();
+        verify_file_signature(&path, &signature, &key)
+            .await
+            .unwrap();
+        std::fs::write(&path, b"hello world!").unwrap();
+        let err = verify_file_signature(&path, &signature, &key)
+            .await
+            .unwrap_err();
+        assert!(err.is::<UnauthorizedArtifact>());
+        _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn resumed_download_hashes_existing_bytes() {
+        let dir = temp_dir("resume-sha256");
+        let path = dir.join("file").to_string_lossy().into_owned();
+        std::fs::write(partial_path(&path, None), b"hello ").unwrap();
+        let resumed = concat!(
+            "HTTP/1.1 206 Partial Content\r\n",
+            "Content-Range: bytes 6-10/11\r\n",
+            "Content-Length: 5\r\n",
+            "Connection: close\r\n\r\n",
+            "world"
+        );
+        let (url, _) = serve(vec![resumed.as_bytes().to_vec()]).await;
+        let options = DownloadOptions {
+            sha256: Some(HELLO_SHA256),
+            resume: true,
+            ..Default::default()
+        };
+        download_file(&client(), &url, &path, options, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"hello world");
+        _ = std::fs::remove_dir_all(dir);
+    }
 }
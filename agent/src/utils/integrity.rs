@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{fs_error, hash_file};
+
+/// File expected to have the SHA-256 digest `sha256`.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct FileCheck {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// How a checked file compared to its expected digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum FileState {
+    Match,
+    Mismatch,
+    Missing,
+    /// The file could not be read, e.g. for lack of permission or because it's a directory
+    Error,
+    /// Not hashed because the byte limit of the task was reached
+    Skipped,
+}
+
+/// Result of checking one file.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FileResult {
+    pub path: String,
+    pub state: FileState,
+    /// Digest the file actually has, set for matching and mismatching files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Compare files against their expected SHA-256 digests, in the order given.
+///
+/// At most `max_bytes` are hashed in total, files that would go beyond it are skipped. Files
+/// are hashed one at a time so a cancelled task stops between files.
+pub(crate) async fn verify_files(checks: Vec<FileCheck>, max_bytes: u64) -> Vec<FileResult> {
+    let mut remaining = max_bytes;
+    let mut results = Vec::with_capacity(checks.len());
+    for check in checks {
+        let path = check.path.clone();
+        let (result, hashed) = tokio::task::spawn_blocking(move || check_file(check, remaining))
+            .await
+            .unwrap_or_else(|err| {
+                let result = FileResult {
+                    path,
+                    state: FileState::Error,
+                    sha256: None,
+                    error: Some(err.to_string()),
+                };
+                (result, 0)
+            });
+        remaining = remaining.saturating_sub(hashed);
+        results.push(result);
+    }
+    results
+}
+
+/// Hash the file of `check` unless it's bigger than `remaining`, returning the bytes hashed.
+fn check_file(check: FileCheck, remaining: u64) -> (FileResult, u64) {
+    let failed = |state, error: String| {
+        let result = FileResult {
+            path: check.path.clone(),
+            state,
+            sha256: None,
+            error: Some(error),
+        };
+        (result, 0)
+    };
+    let metadata = match std::fs::metadata(&check.path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            let result = FileResult {
+                path: check.path,
+                state: FileState::Missing,
+                sha256: None,
+                error: None,
+            };
+            return (result, 0);
+        }
+        Err(err) => {
+            return failed(
+                FileState::Error,
+                fs_error("read", &check.path, err).to_string(),
+            )
+        }
+    };
+    if !metadata.is_file() {
+        return failed(FileState::Error, format!("{} is not a file", check.path));
+    }
+    if metadata.len() > remaining {
+        return failed(
+            FileState::Skipped,
+            format!(
+                "{} bytes would exceed the hash limit of the task",
+                metadata.len()
+            ),
+        );
+    }
+    let mut hasher = Sha256::new();
+    let hashed = match hash_file(&check.path, &mut hasher) {
+        Ok(hashed) => hashed,
+        Err(err) => {
+            return failed(
+                FileState::Error,
+                format!("Failed to read {}: {}", check.path, err),
+            )
+        }
+    };
+    let actual = format!("{:x}", hasher.finalize());
+    let result = FileResult {
+        state: if actual.eq_ignore_ascii_case(&check.sha256) {
+            FileState::Match
+        } else {
+            FileState::Mismatch
+        },
+        path: check.path,
+        sha256: Some(actual),
+        error: None,
+    };
+    (result, hashed)
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::VecDeque,
+    io::SeekFrom,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{bail, Result};
+use futures_util::future::join_all;
+use log::{info, warn};
+use reqwest::{
+    header::{HeaderMap, CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    time::Duration,
+};
+
+use super::{
+    build_headers, check_disk_space, check_download_size, download_file, file_matches_sha256,
+    finish_partial, hash_file, parse_mode, partial_path, remove_partial_file, set_mode,
+    verify_sha256, ConnectionDropped, DownloadOptions, DownloadOutcome, HttpStatusError,
+    ProgressCallback, RateLimiter, TransferStalled,
+};
+use crate::metrics;
+
+/// Size of the ranges fetched from mirrors, each one fetched from a single mirror.
+const CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Wait of a mirror with nothing left to fetch before it checks whether a failed range was
+/// given back by another mirror.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Range of the file to fetch, `end` exclusive.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    start: u64,
+    end: u64,
+}
+
+/// Progress of a download shared by the mirrors fetching its ranges.
+struct Ranges {
+    /// Ranges no mirror fetched yet
+    pending: Mutex<VecDeque<Chunk>>,
+    /// Ranges being fetched right now
+    in_flight: AtomicUsize,
+    /// Bytes received so far and the progress callback told about them
+    progress: Mutex<(u64, Option<ProgressCallback>)>,
+    total: u64,
+}
+
+impl Ranges {
+    /// Take the next range to fetch, `None` once every range was fetched.
+    async fn next(&self) -> Option<Chunk> {
+        loop {
+            if let Some(chunk) = self.pending.lock().unwrap().pop_front() {
+                self.in_flight.fetch_add(1, Ordering::SeqCst);
+                return Some(chunk);
+            }
+            // A range in flight may still fail and come back
+            if self.in_flight.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Mark `chunk` as fetched, or give it back for another mirror.
+    fn done(&self, chunk: Chunk, fetched: bool) {
+        if !fetched {
+            self.pending.lock().unwrap().push_back(chunk);
+        }
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Count `bytes` received, or dropped again when negative, and report the progress.
+    fn add(&self, bytes: i64) {
+        let mut progress = self.progress.lock().unwrap();
+        let (received, callback) = &mut *progress;
+        *received = received.saturating_add_signed(bytes);
+        if let Some(callback) = callback.as_mut() {
+            callback(*received, Some(self.total));
+        }
+    }
+}
+
+/// Download a file from several mirrors at once, each fetching other ranges of it.
+///
+/// Mirrors must answer `Range` requests. A mirror that fails a range more than
+/// `retry.retries` times in a row is given up and its range is fetched by the others. When no
+/// mirror is usable or all of them are given up the file is downloaded from `origin` on its
+/// own, like by `download_file`. Extra headers are sent to every mirror.
+///
+/// `max_bytes_per_sec` is split evenly among the mirrors. Decompressed downloads always come
+/// from `origin`, as ranges of the compressed bytes can't be written where they belong.
+pub(crate) async fn download_from_mirrors(
+    client: &reqwest::Client,
+    origin: &str,
+    mirrors: &[String],
+    path: &str,
+    options: DownloadOptions<'_>,
+    progress: Option<ProgressCallback>,
+) -> Result<DownloadOutcome> {
+    if options.decompress.is_some() {
+        warn!(
+            "Mirrors can't serve decompressed downloads, download {} from {}",
+            path, origin
+        );
+        return download_file(client, origin, path, options, progress).await;
+    }
+    let mode = options.mode.map(parse_mode).transpose()?;
+    if let Some(expected) = options.sha256 {
+        if file_matches_sha256(path, expected).await {
+            info!("{} already has sha256 {}, skip download", path, expected);
+            if let Some(mode) = mode {
+                set_mode(path, mode).await?;
+            }
+            return Ok(DownloadOutcome::Skipped);
+        }
+    }
+    let headers = build_headers(origin, options.headers)?;
+    let mut usable = Vec::new();
+    let mut total = None;
+    for mirror in mirrors {
+        match probe(client, mirror, &headers).await {
+            Ok(size) if total.is_none_or(|total| total == size) => {
+                total = Some(size);
+                usable.push(mirror.as_str());
+            }
+            Ok(size) => warn!(
+                "Mirror {} has {} bytes instead of {}, skip it",
+                mirror,
+                size,
+                total.unwrap_or_default()
+            ),
+            Err(err) => warn!("Mirror {} is not usable: {:#}", mirror, err),
+        }
+    }
+    let Some(total) = total else {
+        warn!("No usable mirror, download {} from {}", path, origin);
+        return download_file(client, origin, path, options, progress).await;
+    };
+    check_download_size(total, options.max_bytes)?;
+    let part = partial_path(path, options.temp_dir);
+    check_disk_space(&part, total)?;
+    tokio::fs::File::create(&part).await?.set_len(total).await?;
+    info!(
+        "Downloading {} bytes to {} from {} mirrors",
+        total,
+        path,
+        usable.len()
+    );
+    let ranges = Ranges {
+        pending: Mutex::new(
+            (0..total.div_ceil(CHUNK_BYTES))
+                .map(|index| Chunk {
+                    start: index * CHUNK_BYTES,
+                    end: ((index + 1) * CHUNK_BYTES).min(total),
+                })
+                .collect(),
+        ),
+        in_flight: AtomicUsize::new(0),
+        progress: Mutex::new((0, progress)),
+        total,
+    };
+    let rate = options
+        .max_bytes_per_sec
+        .map(|rate| rate / usable.len() as u64);
+    join_all(
+        usable
+            .iter()
+            .map(|mirror| fetch_ranges(client, mirror, &part, &headers, &options, rate, &ranges)),
+    )
+    .await;
+    if !ranges.pending.lock().unwrap().is_empty() {
+        warn!(
+            "Every mirror failed, download {} from {} instead",
+            path, origin
+        );
+        remove_partial_file(&part);
+        let progress = ranges.progress.into_inner().unwrap().1;
+        return download_file(client, origin, path, options, progress).await;
+    }
+    if let Some(expected) = options.sha256 {
+        let (hashed, expected) = (part.clone(), expected.to_string());
+        let verified = tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut hasher = Sha256::new();
+            hash_file(&hashed, &mut hasher)?;
+            Ok(verify_sha256(hasher, &expected)?)
+        })
+        .await?;
+        if let Err(err) = verified {
+            warn!(
+                "File downloaded from mirrors to {} is corrupted: {}",
+                path, err
+            );
+            remove_partial_file(&part);
+            return Err(err);
+        }
+    }
+    if let Some(mode) = mode {
+        set_mode(&part, mode).await?;
+    }
+    finish_partial(&part, path).await?;
+    Ok(DownloadOutcome::Downloaded)
+}
+
+/// Size of the file at `url`, failing unless the server answers range requests.
+async fn probe(client: &reqwest::Client, url: &str, headers: &HeaderMap) -> Result<u64> {
+    let response = client
+        .get(url)
+        .headers(headers.clone())
+        .header(RANGE, "bytes=0-0")
+        .send()
+        .await?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        if !response.status().is_success() {
+            return Err(HttpStatusError {
+                url: url.to_string(),
+                status: response.status(),
+            }
+            .into());
+        }
+        bail!("Server doesn't answer range requests");
+    }
+    content_range_total(&response).ok_or_else(|| anyhow::anyhow!("Invalid Content-Range"))
+}
+
+/// Total size in the `Content-Range` of a response, like `bytes 0-0/1234`.
+fn content_range_total(response: &reqwest::Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range.rsplit_once('/')?.1.parse().ok()
+}
+
+/// Fetch ranges from one mirror until none are left or the mirror is given up.
+async fn fetch_ranges(
+    client: &reqwest::Client,
+    url: &str,
+    part: &str,
+    headers: &HeaderMap,
+    options: &DownloadOptions<'_>,
+    rate: Option<u64>,
+    ranges: &Ranges,
+) {
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(part).await {
+        Ok(file) => file,
+        Err(err) => {
+            warn!("Failed to open partial file {}: {}", part, err);
+            return;
+        }
+    };
+    let mut limiter = rate.map(RateLimiter::new);
+    let mut failures = 0;
+    while let Some(chunk) = ranges.next().await {
+        let mut received = 0;
+        let result = fetch_chunk(
+            client,
+            url,
+            headers,
+            options,
+            chunk,
+            &mut file,
+            limiter.as_mut(),
+            |bytes| {
+                received += bytes;
+                ranges.add(bytes as i64);
+            },
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                ranges.done(chunk, true);
+                failures = 0;
+            }
+            Err(err) => {
+                ranges.add(-(received as i64));
+                ranges.done(chunk, false);
+                failures += 1;
+                if failures > options.retry.retries {
+                    warn!(
+                        "Bytes {}-{} from mirror {} failed: {:#}. Giving up the mirror",
+                        chunk.start,
+                        chunk.end - 1,
+                        url,
+                        err
+                    );
+                    return;
+                }
+                warn!(
+                    "Bytes {}-{} from mirror {} failed: {:#}. Retry {}/{} in {} seconds...",
+                    chunk.start,
+                    chunk.end - 1,
+                    url,
+                    err,
+                    failures,
+                    options.retry.retries,
+                    options.retry.delay.as_secs()
+                );
+                tokio::time::sleep(options.retry.delay).await;
+            }
+        }
+    }
+}
+
+/// Fetch `chunk` from `url` and write it where it belongs in `file`.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_chunk(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &HeaderMap,
+    options: &DownloadOptions<'_>,
+    chunk: Chunk,
+    file: &mut tokio::fs::File,
+    mut limiter: Option<&mut RateLimiter>,
+    mut received: impl FnMut(u64),
+) -> Result<()> {
+    let mut response = client
+        .get(url)
+        .headers(headers.clone())
+        .header(RANGE, format!("bytes={}-{}", chunk.start, chunk.end - 1))
+        .send()
+        .await?;
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        if !response.status().is_success() {
+            return Err(HttpStatusError {
+                url: url.to_string(),
+                status: response.status(),
+            }
+            .into());
+        }
+        bail!("Server ignored the range request");
+    }
+    let expected = format!("bytes {}-{}/", chunk.start, chunk.end - 1);
+    let range = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|range| range.to_str().ok())
+        .unwrap_or_default();
+    if !range.starts_with(&expected) {
+        bail!(
+            "Server answered range {:?} instead of {:?}",
+            range,
+            expected
+        );
+    }
+    file.seek(SeekFrom::Start(chunk.start)).await?;
+    let length = chunk.end - chunk.start;
+    let mut written = 0;
+    while written < length {
+        let next = match options.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, response.chunk())
+                .await
+                .map_err(|_| TransferStalled { timeout })?,
+            None => response.chunk().await,
+        };
+        let data = next.map_err(|err| {
+            anyhow::Error::new(err).context(ConnectionDropped {
+                received: chunk.start + written,
+                total: Some(chunk.end),
+            })
+        })?;
+        let Some(data) = data else {
+            return Err(ConnectionDropped {
+                received: chunk.start + written,
+                total: Some(chunk.end),
+            }
+            .into());
+        };
+        // A server sending more than asked for is cut off at the end of the range
+        let data = &data[..data.len().min((length - written) as usize)];
+        file.write_all(data).await?;
+        written += data.len() as u64;
+        metrics::downloaded(data.len() as u64);
+        received(data.len() as u64);
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.consume(data.len() as u64).await;
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use super::{execute_command_with_output, CommandExit, CommandOptions};
+
+/// A package installed on the machine, as reported by its package manager.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Package {
+    pub name: String,
+    pub version: String,
+    /// Architecture the package was built for, e.g. `amd64` or `x86_64`
+    pub arch: String,
+}
+
+/// Installed packages of the machine.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PackageList {
+    /// Package manager that listed the packages, `dpkg` or `rpm`
+    pub manager: &'static str,
+    pub packages: Vec<Package>,
+    /// Whether the list was cut off at the size limit
+    pub truncated: bool,
+}
+
+/// Package managers that can be queried, in order of preference.
+///
+/// Each lists one package per line as tab separated name, version and architecture.
+const MANAGERS: &[(&str, &str, &[&str])] = &[
+    (
+        "dpkg",
+        "dpkg-query",
+        &["-W", "-f", "${Package}\\t${Version}\\t${Architecture}\\n"],
+    ),
+    (
+        "rpm",
+        "rpm",
+        &[
+            "-qa",
+            "--qf",
+            "%{NAME}\\t%{VERSION}-%{RELEASE}\\t%{ARCH}\\n",
+        ],
+    ),
+];
+
+/// List the installed packages with the first package manager found in `PATH`.
+///
+/// At most `max_bytes` of package manager output are read, packages beyond it are left out
+/// and reported through `PackageList::truncated`.
+pub(crate) async fn query_packages(
+    options: CommandOptions<'_>,
+    max_bytes: usize,
+) -> Result<PackageList> {
+    let Some((manager, cmd, args)) = MANAGERS
+        .iter()
+        .find(|(_, cmd, _)| find_in_path(cmd))
+        .copied()
+    else {
+        bail!("No supported package manager found, dpkg or rpm is required");
+    };
+    let args = args.iter().map(|arg| arg.to_string()).collect();
+    let output = execute_command_with_output(&cmd.to_string(), args, options, max_bytes).await?;
+    if output.exit != CommandExit::Code(0) {
+        bail!(
+            "{} exited with code {}: {}",
+            cmd,
+            output.exit.code(),
+            output.output.lines().next().unwrap_or_default()
+        );
+    }
+    let mut lines: Vec<&str> = output.output.lines().collect();
+    if output.truncated {
+        // The last line may be cut off
+        lines.pop();
+    }
+    let packages = lines
+        .into_iter()
+        .filter_map(|line| {
+            // Other lines are warnings on stderr, which is mixed into the output
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(version), Some(arch), None) if !name.is_empty() => {
+                    Some(Package {
+                        name: name.to_string(),
+                        version: version.to_string(),
+                        arch: arch.to_string(),
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    Ok(PackageList {
+        manager,
+        packages,
+        truncated: output.truncated,
+    })
+}
+
+/// Whether an executable named `cmd` is in one of the directories of `PATH`.
+fn find_in_path(cmd: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(cmd).is_file()))
+}
@@ -0,0 +1,111 @@
+use log::warn;
+use serde::Serialize;
+
+/// Description of the machine the agent runs on, sent to controller on registration so it
+/// can pick the tasks fitting this machine.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SystemInfo {
+    /// Operating system, e.g. `linux`
+    pub os: String,
+    /// Kernel release as reported by uname, `None` if unknown
+    pub kernel_version: Option<String>,
+    /// CPU architecture, e.g. `x86_64` or `aarch64`
+    pub arch: String,
+    /// Physical memory in bytes, `None` if unknown
+    pub total_memory_bytes: Option<u64>,
+    /// Number of CPUs the agent may use
+    pub cpu_count: usize,
+}
+
+impl SystemInfo {
+    /// Gather the description of this machine. Parts that can't be read are left empty.
+    pub(crate) fn collect() -> Self {
+        let cpu_count = std::thread::available_parallelism()
+            .map(|count| count.get())
+            .inspect_err(|err| warn!("Failed to get CPU count: {}", err))
+            .unwrap_or(1);
+        SystemInfo {
+            os: std::env::consts::OS.to_string(),
+            kernel_version: kernel_version(),
+            arch: std::env::consts::ARCH.to_string(),
+            total_memory_bytes: total_memory_bytes(),
+            cpu_count,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kernel_version() -> Option<String> {
+    // SAFETY: `uname` only writes into the zero-initialized struct we own
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        warn!(
+            "Failed to get kernel version: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    // SAFETY: `uname` fills `release` with a NUL-terminated string
+    let release = unsafe { std::ffi::CStr::from_ptr(name.release.as_ptr()) };
+    Some(release.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn kernel_version() -> Option<String> {
+    None
+}
+
+#[cfg(unix)]
+fn total_memory_bytes() -> Option<u64> {
+    // SAFETY: `sysconf` has no preconditions
+    let (pages, page_size) = unsafe {
+        (
+            libc::sysconf(libc::_SC_PHYS_PAGES),
+            libc::sysconf(libc::_SC_PAGESIZE),
+        )
+    };
+    if pages <= 0 || page_size <= 0 {
+        warn!("Failed to get total memory");
+        return None;
+    }
+    Some(pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn total_memory_bytes() -> Option<u64> {
+    None
+}
+
+/// Effective user id of the agent process, `None` on platforms without one.
+#[cfg(unix)]
+pub(crate) fn effective_uid() -> Option<u32> {
+    // SAFETY: `geteuid` has no preconditions and can't fail
+    Some(unsafe { libc::geteuid() })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn effective_uid() -> Option<u32> {
+    None
+}
+
+/// Hostname of the machine, `None` if it can't be read.
+#[cfg(unix)]
+pub(crate) fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: `gethostname` writes at most `name.len()` bytes into the buffer we own
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        warn!(
+            "Failed to get hostname: {}",
+            std::io::Error::last_os_error()
+        );
+        return None;
+    }
+    // The name may be cut off without a NUL when it's too long
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}